@@ -0,0 +1,174 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{heap::Handle, opcode::OpCode, value::Value};
+
+/// A position in the source text. Both fields are 1-based.
+#[derive(Default, Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Location {
+    pub line: usize,
+    pub column: usize,
+}
+
+/// `count` consecutive bytes of code that were all emitted from `location`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LineRun {
+    pub location: Location,
+    pub count: usize,
+}
+
+/// A compiled sequence of bytecode along with the constants it references.
+/// Source locations are kept run-length encoded in `lines`, since every byte
+/// of an instruction and most instructions of an expression share one.
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub code: Vec<u8>,
+    pub constants: Vec<Value>,
+    pub lines: Vec<LineRun>,
+    /// Where each number and object already in `constants` is, so
+    /// [`Chunk::add_constant`] can reuse it without a scan.
+    constant_indices: HashMap<ConstantKey, usize>,
+}
+
+/// A constant as it is deduplicated: numbers by their bits, so `0` and `-0`
+/// stay distinct, and objects by handle.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum ConstantKey {
+    Number(u64),
+    Obj(Handle),
+}
+
+impl ConstantKey {
+    fn new(value: Value) -> Option<Self> {
+        match value {
+            Value::Number(number) => Some(ConstantKey::Number(number.to_bits())),
+            Value::Obj(handle) => Some(ConstantKey::Obj(handle)),
+            _ => None,
+        }
+    }
+}
+
+impl Chunk {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reassembles a chunk from its parts, as loaded from a `.loxc` file.
+    pub fn from_parts(code: Vec<u8>, constants: Vec<Value>, lines: Vec<LineRun>) -> Self {
+        let mut constant_indices = HashMap::new();
+        for (index, &constant) in constants.iter().enumerate().rev() {
+            if let Some(key) = ConstantKey::new(constant) {
+                constant_indices.insert(key, index);
+            }
+        }
+
+        Self {
+            code,
+            constants,
+            lines,
+            constant_indices,
+        }
+    }
+
+    pub fn write(&mut self, byte: u8, location: Location) {
+        self.code.push(byte);
+
+        match self.lines.last_mut() {
+            Some(run) if run.location == location => run.count += 1,
+            _ => self.lines.push(LineRun { location, count: 1 }),
+        }
+    }
+
+    /// Returns the source location of the byte at `offset`.
+    pub fn location(&self, offset: usize) -> Location {
+        let mut end = 0;
+        for run in &self.lines {
+            end += run.count;
+            if offset < end {
+                return run.location;
+            }
+        }
+        Location::default()
+    }
+
+    pub fn line(&self, offset: usize) -> usize {
+        self.location(offset).line
+    }
+
+    pub fn len(&self) -> usize {
+        self.code.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.code.is_empty()
+    }
+
+    /// Adds `value` to the constant table and returns its index. Numbers and
+    /// strings already in the table are reused rather than stored twice.
+    pub fn add_constant(&mut self, value: Value) -> usize {
+        let key = ConstantKey::new(value);
+        if let Some(&index) = key.and_then(|key| self.constant_indices.get(&key)) {
+            return index;
+        }
+
+        self.constants.push(value);
+        let index = self.constants.len() - 1;
+        if let Some(key) = key {
+            self.constant_indices.insert(key, index);
+        }
+        index
+    }
+
+    pub fn opcode(&self, offset: usize) -> Result<OpCode, u8> {
+        OpCode::try_from(self.code[offset])
+    }
+
+    pub fn read_u16(&self, offset: usize) -> u16 {
+        u16::from_be_bytes([self.code[offset], self.code[offset + 1]])
+    }
+
+    pub fn read_u24(&self, offset: usize) -> usize {
+        u32::from_be_bytes([
+            0,
+            self.code[offset],
+            self.code[offset + 1],
+            self.code[offset + 2],
+        ]) as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn line_runs() {
+        let mut chunk = Chunk::new();
+        let first = Location { line: 1, column: 1 };
+        let second = Location { line: 3, column: 5 };
+        chunk.write(OpCode::Nil.into(), first);
+        chunk.write(OpCode::Print.into(), first);
+        chunk.write(OpCode::Constant.into(), second);
+        chunk.write(0, second);
+        chunk.write(OpCode::Return.into(), first);
+
+        assert_eq!(chunk.lines.len(), 3);
+        assert_eq!(chunk.location(1), first);
+        assert_eq!(chunk.location(3), second);
+        assert_eq!(chunk.line(4), 1);
+    }
+
+    #[test]
+    fn reuses_constants() {
+        let mut chunk = Chunk::new();
+        assert_eq!(chunk.add_constant(Value::Number(1.0)), 0);
+        assert_eq!(chunk.add_constant(Value::Number(0.0)), 1);
+        assert_eq!(chunk.add_constant(Value::Number(-0.0)), 2);
+        assert_eq!(chunk.add_constant(Value::Number(1.0)), 0);
+
+        let mut loaded = Chunk::from_parts(vec![], chunk.constants.clone(), vec![]);
+        assert_eq!(loaded.add_constant(Value::Number(-0.0)), 2);
+        assert_eq!(loaded.add_constant(Value::Number(2.0)), 3);
+    }
+}
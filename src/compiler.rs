@@ -1,17 +1,22 @@
-use std::{collections::HashMap, ops::Add};
+use std::{collections::HashMap, mem, ops::Add};
 
 use lazy_static::lazy_static;
 
 use crate::{
-    opcode::OpCode,
+    chunk::{Chunk, Location},
+    diagnostic::Diagnostic,
+    disassembler::disassemble_chunk,
+    heap::{Handle, Heap},
+    opcode::{OpCode, UpvalueIndex},
     scanner::{Scanner, Token, TokenType, TokenValue},
-    value::{Obj, Value},
-    vm::VMError,
+    value::{Function, Obj, Value},
 };
 
 #[derive(Default, Debug, Clone)]
 pub struct Compiler {
-    parser: Parser,
+    debug: bool,
+    /// How many sources have been compiled, used to number the next one.
+    sources: usize,
 }
 
 impl Compiler {
@@ -19,19 +24,64 @@ impl Compiler {
         Self::default()
     }
 
-    pub fn compile(&mut self, source: &str) -> Result<Vec<OpCode>, VMError> {
-        self.parser.scanner.input(source);
-        self.parser.advance();
-        while !self.parser.r#match(&TokenType::Eof) {
-            self.parser.declaration();
+    /// When set, the disassembly of each successfully compiled script is
+    /// printed to stderr.
+    pub fn set_debug(&mut self, debug: bool) {
+        self.debug = debug;
+    }
+
+    /// Compiles `source` into the top-level script function, or returns every
+    /// diagnostic reported along the way. The script, the functions it
+    /// defines and their string constants are allocated in `heap`, which
+    /// should be the heap of the VM that will run the result.
+    pub fn compile(&mut self, source: &str, heap: &mut Heap) -> Result<Handle, Vec<Diagnostic>> {
+        self.compile_as(source, heap, false)
+    }
+
+    /// Like [`Compiler::compile`], but for a REPL entry: if the entry ends
+    /// with an expression statement, its semicolon is optional and the script
+    /// returns the expression's value instead of discarding it.
+    pub fn compile_repl(
+        &mut self,
+        source: &str,
+        heap: &mut Heap,
+    ) -> Result<Handle, Vec<Diagnostic>> {
+        self.compile_as(source, heap, true)
+    }
+
+    fn compile_as(
+        &mut self,
+        source: &str,
+        heap: &mut Heap,
+        repl: bool,
+    ) -> Result<Handle, Vec<Diagnostic>> {
+        self.sources += 1;
+
+        let mut parser = Parser::new(source, heap);
+        parser.debug = self.debug;
+        parser.source = self.sources;
+        parser.repl = repl;
+        parser
+            .states
+            .push(FunctionState::new(FunctionType::Script, String::new()));
+        parser.advance();
+        while !parser.r#match(&TokenType::Eof) {
+            parser.top_level = true;
+            parser.declaration();
         }
-        self.parser.end_compiler();
+        let (function, _) = parser.end_compiler();
 
-        if self.parser.had_error {
-            Err(VMError::CompileTime)
-        } else {
-            Ok(self.parser.ops.clone())
+        if parser.had_error {
+            return Err(parser.diagnostics);
+        }
+
+        if parser.debug {
+            eprint!(
+                "{}",
+                disassemble_chunk(&function.chunk, &function.to_string(), parser.heap)
+            );
         }
+        Ok(parser.heap.alloc(Obj::Function(function)))
     }
 }
 
@@ -110,6 +160,8 @@ pub enum PrefixRule {
     Literal,
     String,
     Variable,
+    This,
+    Super,
 }
 
 #[non_exhaustive]
@@ -118,6 +170,11 @@ pub enum InfixRule {
     #[default]
     None,
     Binary,
+    And,
+    Or,
+    Call,
+    Dot,
+    Subscript,
 }
 
 #[derive(Default, Debug, Clone, PartialEq, PartialOrd, Eq, Ord, Hash)]
@@ -133,6 +190,15 @@ lazy_static! {
             TokenType::LeftParen,
             ParseRule {
                 prefix: PrefixRule::Grouping,
+                infix: InfixRule::Call,
+                precedence: Precedence::Call,
+            },
+        ),
+        (
+            TokenType::LeftBracket,
+            ParseRule {
+                infix: InfixRule::Subscript,
+                precedence: Precedence::Call,
                 ..Default::default()
             },
         ),
@@ -265,6 +331,44 @@ lazy_static! {
                 ..Default::default()
             },
         ),
+        (
+            TokenType::This,
+            ParseRule {
+                prefix: PrefixRule::This,
+                ..Default::default()
+            },
+        ),
+        (
+            TokenType::Super,
+            ParseRule {
+                prefix: PrefixRule::Super,
+                ..Default::default()
+            },
+        ),
+        (
+            TokenType::Dot,
+            ParseRule {
+                infix: InfixRule::Dot,
+                precedence: Precedence::Call,
+                ..Default::default()
+            },
+        ),
+        (
+            TokenType::And,
+            ParseRule {
+                infix: InfixRule::And,
+                precedence: Precedence::And,
+                ..Default::default()
+            },
+        ),
+        (
+            TokenType::Or,
+            ParseRule {
+                infix: InfixRule::Or,
+                precedence: Precedence::Or,
+                ..Default::default()
+            },
+        ),
     ]);
 }
 
@@ -272,20 +376,112 @@ fn get_rule(token_type: &TokenType) -> ParseRule {
     RULES.get(token_type).cloned().unwrap_or_default()
 }
 
+const UINT8_COUNT: usize = u8::MAX as usize + 1;
+const UINT24_MAX: usize = (1 << 24) - 1;
+
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub struct Local {
+    name: String,
+    /// `None` until the variable's initializer has been compiled.
+    depth: Option<usize>,
+    is_captured: bool,
+}
+
+#[derive(Default, Debug, Clone, PartialEq, Eq)]
+pub enum FunctionType {
+    Function,
+    Initializer,
+    Method,
+    #[default]
+    Script,
+}
+
+/// Compilation state for the function currently being compiled. Nested
+/// function declarations push a new state onto `Parser::states`.
+#[derive(Default, Debug, Clone)]
+pub struct FunctionState {
+    function: Function,
+    function_type: FunctionType,
+    locals: Vec<Local>,
+    upvalues: Vec<UpvalueIndex>,
+    scope_depth: usize,
+}
+
+impl FunctionState {
+    fn new(function_type: FunctionType, name: String) -> Self {
+        Self {
+            function: Function {
+                name,
+                ..Default::default()
+            },
+            // Slot zero holds the function being called, or the receiver
+            // for methods, where it is reachable as `this`.
+            locals: vec![Local {
+                name: match function_type {
+                    FunctionType::Function | FunctionType::Script => String::new(),
+                    FunctionType::Initializer | FunctionType::Method => "this".to_string(),
+                },
+                depth: Some(0),
+                is_captured: false,
+            }],
+            function_type,
+            upvalues: vec![],
+            scope_depth: 0,
+        }
+    }
+}
+
+/// Compilation state for the class whose body is currently being compiled.
 #[derive(Default, Debug, Clone)]
-pub struct Parser {
+pub struct ClassState {
+    has_superclass: bool,
+}
+
+#[derive(Debug)]
+pub struct Parser<'a> {
     pub scanner: Scanner,
     pub previous: Option<Token>,
     pub current: Option<Token>,
     pub had_error: bool,
     pub panic_mode: bool,
-    pub ops: Vec<OpCode>,
+    pub diagnostics: Vec<Diagnostic>,
     pub debug: bool,
+    /// Whether a final top-level expression statement returns its value. See
+    /// [`Compiler::compile_repl`].
+    pub repl: bool,
+    /// Set for each declaration parsed directly by the script's top-level
+    /// loop and cleared as soon as a statement starts, so only an expression
+    /// statement at the top level can become the REPL result, never one
+    /// nested as the body of a loop or `if`.
+    top_level: bool,
+    /// The number recorded as [`Function::source`] on every function
+    /// compiled.
+    pub source: usize,
+    pub states: Vec<FunctionState>,
+    pub classes: Vec<ClassState>,
+    pub heap: &'a mut Heap,
 }
 
-impl Parser {
-    pub fn new() -> Self {
-        Self::default()
+impl<'a> Parser<'a> {
+    pub fn new(source: &str, heap: &'a mut Heap) -> Self {
+        let mut scanner = Scanner::new();
+        scanner.input(source);
+
+        Self {
+            scanner,
+            previous: None,
+            current: None,
+            had_error: false,
+            panic_mode: false,
+            diagnostics: vec![],
+            debug: false,
+            repl: false,
+            top_level: false,
+            source: 0,
+            states: vec![],
+            classes: vec![],
+            heap,
+        }
     }
 
     pub fn consume(&mut self, token_type: &TokenType, message: &str) {
@@ -299,95 +495,482 @@ impl Parser {
     }
 
     fn advance(&mut self) {
-        self.previous = self.current.clone();
+        self.previous = self.current.take();
 
-        self.current = Some(self.scanner.scan_token());
-        if let Some(current) = &self.current {
-            if current.r#type != TokenType::Error {
-                return;
+        loop {
+            let token = self.scanner.scan_token();
+            if token.r#type == TokenType::Comment {
+                continue;
+            }
+
+            let error = match &token.value {
+                Some(TokenValue::Error(message)) => Some(message.clone()),
+                _ => None,
+            };
+            self.current = Some(token);
+
+            match error {
+                Some(message) => {
+                    self.error(&message);
+                }
+                None => break,
             }
         }
-        self.error("found error token");
     }
 
-    fn error(&mut self, message: &str) {
-        if let Some(token) = &self.current {
-            self.error_at(&token.clone(), message);
-        }
+    fn error(&mut self, message: &str) -> Option<&mut Diagnostic> {
+        let token = self.current.clone()?;
+        self.error_at(&token, message)
     }
 
-    fn error_at(&mut self, token: &Token, message: &str) {
+    fn error_at_previous(&mut self, message: &str) -> Option<&mut Diagnostic> {
+        let token = self.previous.clone()?;
+        self.error_at(&token, message)
+    }
+
+    /// Records an error at `token` and returns it so callers can attach notes
+    /// or help. Errors reported while already panicking are dropped, since
+    /// they are usually knock-on effects of the first one.
+    fn error_at(&mut self, token: &Token, message: &str) -> Option<&mut Diagnostic> {
         if self.panic_mode {
-            return;
+            return None;
         }
         self.panic_mode = true;
+        self.had_error = true;
 
-        eprint!("[line {}] Error", token.line);
+        let (start, end) = match token.r#type {
+            TokenType::Eof => (
+                self.scanner.byte_offset(token.start),
+                self.scanner.byte_offset(token.start),
+            ),
+            _ => (
+                self.scanner.byte_offset(token.start),
+                self.scanner.byte_offset(token.start + token.length),
+            ),
+        };
+        let location = Location {
+            line: token.line,
+            column: token.column,
+        };
 
-        match token.r#type {
-            TokenType::Eof => {
-                eprint!(" at end");
-            }
-            TokenType::Error => {}
-            _ => {
-                eprint!(" at {} to {}", token.start, token.start + token.length);
-            }
-        }
-        eprintln!(": {}", message);
-        self.had_error = true;
+        self.diagnostics
+            .push(Diagnostic::error(message, start..end, location));
+        self.diagnostics.last_mut()
+    }
+
+    fn state(&self) -> &FunctionState {
+        self.states.last().unwrap()
     }
 
-    fn end_compiler(&mut self) {
+    fn state_mut(&mut self) -> &mut FunctionState {
+        self.states.last_mut().unwrap()
+    }
+
+    fn chunk(&mut self) -> &mut Chunk {
+        &mut self.state_mut().function.chunk
+    }
+
+    fn end_compiler(&mut self) -> (Function, Vec<UpvalueIndex>) {
         self.emit_return();
-        if !self.had_error && self.debug {
-            dbg!(&self.current);
-        }
+        let mut state = self.states.pop().unwrap();
+        state.function.source = self.source;
+        (state.function, state.upvalues)
     }
 
     fn emit_return(&mut self) {
+        if self.state().function_type == FunctionType::Initializer {
+            self.emit_bytes(OpCode::GetLocal, 0);
+        } else {
+            self.emit_byte(OpCode::Nil);
+        }
+
         self.emit_byte(OpCode::Return);
     }
 
-    fn emit_byte(&mut self, opcode: OpCode) {
-        self.ops.push(opcode);
+    fn emit_byte(&mut self, byte: impl Into<u8>) {
+        let location = self.previous_location();
+        self.emit_byte_at(byte, location);
     }
 
-    fn emit_bytes(&mut self, bytes: &[OpCode]) {
-        for byte in bytes {
-            self.emit_byte(byte.clone());
-        }
+    fn emit_byte_at(&mut self, byte: impl Into<u8>, location: Location) {
+        self.chunk().write(byte.into(), location);
+    }
+
+    fn previous_location(&self) -> Location {
+        self.previous
+            .as_ref()
+            .map_or_else(Location::default, |token| Location {
+                line: token.line,
+                column: token.column,
+            })
+    }
+
+    fn emit_bytes(&mut self, first: impl Into<u8>, second: impl Into<u8>) {
+        self.emit_byte(first);
+        self.emit_byte(second);
     }
 
     fn emit_constant(&mut self, value: Value) {
-        self.emit_byte(OpCode::Constant(value));
+        let constant = self.make_constant(value);
+        self.emit_with_constant(OpCode::Constant, constant);
+    }
+
+    /// Emits `op` with the constant index `constant`, switching to the long
+    /// form of `op` once the constant table outgrows an 8-bit index.
+    fn emit_with_constant(&mut self, op: OpCode, constant: usize) {
+        if let Ok(constant) = u8::try_from(constant) {
+            self.emit_bytes(op, constant);
+        } else {
+            let long = op.long().expect("only opcodes with a constant operand");
+            let [_, high, middle, low] = (constant as u32).to_be_bytes();
+            self.emit_bytes(long, high);
+            self.emit_bytes(middle, low);
+        }
+    }
+
+    fn make_constant(&mut self, value: Value) -> usize {
+        let constant = self.chunk().add_constant(value);
+
+        if constant > UINT24_MAX {
+            self.error_at_previous("Too many constants in one chunk.");
+            return 0;
+        }
+        constant
+    }
+
+    /// Emits a jump with a placeholder offset and returns the offset of its
+    /// operand so it can be back-patched once the jump target is known.
+    fn emit_jump(&mut self, opcode: OpCode) -> usize {
+        self.emit_byte(opcode);
+        self.emit_bytes(0xff, 0xff);
+        self.chunk().len() - 2
+    }
+
+    fn emit_loop(&mut self, loop_start: usize) {
+        self.emit_byte(OpCode::Loop);
+
+        let Ok(offset) = u16::try_from(self.chunk().len() - loop_start + 2) else {
+            self.error_at_previous("Loop body too large.");
+            return;
+        };
+
+        let [high, low] = offset.to_be_bytes();
+        self.emit_bytes(high, low);
+    }
+
+    fn patch_jump(&mut self, jump: usize) {
+        // -2 to adjust for the jump offset itself.
+        let Ok(offset) = u16::try_from(self.chunk().len() - jump - 2) else {
+            self.error_at_previous("Too much code to jump over.");
+            return;
+        };
+
+        let [high, low] = offset.to_be_bytes();
+        let chunk = self.chunk();
+        chunk.code[jump] = high;
+        chunk.code[jump + 1] = low;
     }
 
     fn declaration(&mut self) {
-        if self.r#match(&TokenType::Var) {
+        let top_level = mem::take(&mut self.top_level);
+        if self.r#match(&TokenType::Class) {
+            self.class_declaration();
+        } else if self.r#match(&TokenType::Fun) {
+            self.fun_declaration();
+        } else if self.r#match(&TokenType::Var) {
             self.var_declaration();
         } else {
+            self.top_level = top_level;
             self.statement();
         }
 
         if self.panic_mode {
-            dbg!("synchronizing");
             self.synchronize();
-            dbg!(self.panic_mode);
         }
     }
 
     fn statement(&mut self) {
+        let top_level = mem::take(&mut self.top_level);
         if self.r#match(&TokenType::Print) {
             self.print_statement();
+        } else if self.r#match(&TokenType::For) {
+            self.for_statement();
+        } else if self.r#match(&TokenType::If) {
+            self.if_statement();
+        } else if self.r#match(&TokenType::Return) {
+            self.return_statement();
+        } else if self.r#match(&TokenType::While) {
+            self.while_statement();
+        } else if self.r#match(&TokenType::LeftBrace) {
+            self.begin_scope();
+            self.block();
+            self.end_scope();
+        } else {
+            self.expression_statement(top_level);
+        }
+    }
+
+    fn if_statement(&mut self) {
+        self.consume(&TokenType::LeftParen, "Expect '(' after 'if'.");
+        self.expression();
+        self.consume(&TokenType::RightParen, "Expect ')' after condition.");
+
+        let then_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop);
+        self.statement();
+
+        let else_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(then_jump);
+        self.emit_byte(OpCode::Pop);
+
+        if self.r#match(&TokenType::Else) {
+            self.statement();
+        }
+        self.patch_jump(else_jump);
+    }
+
+    fn return_statement(&mut self) {
+        if self.state().function_type == FunctionType::Script {
+            self.error_at_previous("Can't return from top-level code.");
+        }
+
+        if self.r#match(&TokenType::Semicolon) {
+            self.emit_return();
+        } else {
+            if self.state().function_type == FunctionType::Initializer {
+                if let Some(diagnostic) =
+                    self.error_at_previous("Can't return a value from an initializer.")
+                {
+                    diagnostic
+                        .notes
+                        .push("initializers always return `this`".to_string());
+                    diagnostic.help = Some("use a bare `return;` to exit early".to_string());
+                }
+            }
+
+            self.expression();
+            self.consume(&TokenType::Semicolon, "Expect ';' after return value.");
+            self.emit_byte(OpCode::Return);
+        }
+    }
+
+    fn while_statement(&mut self) {
+        let loop_start = self.chunk().len();
+
+        self.consume(&TokenType::LeftParen, "Expect '(' after 'while'.");
+        self.expression();
+        self.consume(&TokenType::RightParen, "Expect ')' after condition.");
+
+        let exit_jump = self.emit_jump(OpCode::JumpIfFalse);
+        self.emit_byte(OpCode::Pop);
+        self.statement();
+        self.emit_loop(loop_start);
+
+        self.patch_jump(exit_jump);
+        self.emit_byte(OpCode::Pop);
+    }
+
+    fn for_statement(&mut self) {
+        self.begin_scope();
+
+        self.consume(&TokenType::LeftParen, "Expect '(' after 'for'.");
+        if self.r#match(&TokenType::Semicolon) {
+            // No initializer.
+        } else if self.r#match(&TokenType::Var) {
+            self.var_declaration();
         } else {
-            self.expression_statement();
+            self.expression_statement(false);
         }
+
+        let mut loop_start = self.chunk().len();
+
+        let mut exit_jump = None;
+        if !self.r#match(&TokenType::Semicolon) {
+            self.expression();
+            self.consume(&TokenType::Semicolon, "Expect ';' after loop condition.");
+
+            exit_jump = Some(self.emit_jump(OpCode::JumpIfFalse));
+            self.emit_byte(OpCode::Pop);
+        }
+
+        if !self.r#match(&TokenType::RightParen) {
+            let body_jump = self.emit_jump(OpCode::Jump);
+            let increment_start = self.chunk().len();
+
+            self.expression();
+            self.emit_byte(OpCode::Pop);
+            self.consume(&TokenType::RightParen, "Expect ')' after for clauses.");
+
+            self.emit_loop(loop_start);
+            loop_start = increment_start;
+            self.patch_jump(body_jump);
+        }
+
+        self.statement();
+        self.emit_loop(loop_start);
+
+        if let Some(exit_jump) = exit_jump {
+            self.patch_jump(exit_jump);
+            self.emit_byte(OpCode::Pop);
+        }
+
+        self.end_scope();
+    }
+
+    fn block(&mut self) {
+        while !self.check(&TokenType::RightBrace) && !self.check(&TokenType::Eof) {
+            self.declaration();
+        }
+
+        self.consume(&TokenType::RightBrace, "Expect '}' after block.");
+    }
+
+    fn begin_scope(&mut self) {
+        self.state_mut().scope_depth += 1;
     }
 
-    fn expression_statement(&mut self) {
+    fn end_scope(&mut self) {
+        self.state_mut().scope_depth -= 1;
+
+        let scope_depth = self.state().scope_depth;
+        while let Some(is_captured) = self
+            .state()
+            .locals
+            .last()
+            .filter(|local| local.depth.is_some_and(|depth| depth > scope_depth))
+            .map(|local| local.is_captured)
+        {
+            if is_captured {
+                self.emit_byte(OpCode::CloseUpvalue);
+            } else {
+                self.emit_byte(OpCode::Pop);
+            }
+            self.state_mut().locals.pop();
+        }
+    }
+
+    fn expression_statement(&mut self, top_level: bool) {
         self.expression();
+        if top_level && self.is_repl_result() {
+            self.emit_byte(OpCode::Return);
+            return;
+        }
+
         self.consume(&TokenType::Semicolon, "Expect ';' after expression.");
+        if top_level && self.is_repl_result() {
+            self.emit_byte(OpCode::Return);
+        } else {
+            self.emit_byte(OpCode::Pop);
+        }
+    }
+
+    /// True if the top-level expression just compiled is the last thing in a
+    /// REPL entry.
+    fn is_repl_result(&mut self) -> bool {
+        self.repl && self.states.len() == 1 && self.check(&TokenType::Eof)
+    }
+
+    fn class_declaration(&mut self) {
+        self.consume(&TokenType::Identifier, "Expect class name.");
+        let class_token = self.previous.clone().unwrap();
+        let class_name = self.identifier_name(&class_token);
+        let name_constant = self.identifier_constant(&class_token);
+        self.declare_variable();
+
+        self.emit_with_constant(OpCode::Class, name_constant);
+        self.define_variable(name_constant);
+
+        self.classes.push(ClassState::default());
+
+        if self.r#match(&TokenType::Less) {
+            self.consume(&TokenType::Identifier, "Expect superclass name.");
+            self.variable(false);
+
+            let superclass_name = self.identifier_name(&self.previous.clone().unwrap());
+            if superclass_name == class_name {
+                self.error_at_previous("A class can't inherit from itself.");
+            }
+
+            // The superclass lives in a scope of its own so every method
+            // closure can capture it as `super`.
+            self.begin_scope();
+            self.add_local("super".to_string());
+            self.define_variable(0);
+
+            self.named_variable(&class_token, false);
+            self.emit_byte(OpCode::Inherit);
+            self.classes.last_mut().unwrap().has_superclass = true;
+        }
+
+        self.named_variable(&class_token, false);
+        self.consume(&TokenType::LeftBrace, "Expect '{' before class body.");
+        while !self.check(&TokenType::RightBrace) && !self.check(&TokenType::Eof) {
+            self.method();
+        }
+        self.consume(&TokenType::RightBrace, "Expect '}' after class body.");
         self.emit_byte(OpCode::Pop);
+
+        if self.classes.pop().unwrap().has_superclass {
+            self.end_scope();
+        }
+    }
+
+    fn method(&mut self) {
+        self.consume(&TokenType::Identifier, "Expect method name.");
+        let name_token = self.previous.clone().unwrap();
+        let constant = self.identifier_constant(&name_token);
+
+        let function_type = if self.identifier_name(&name_token) == "init" {
+            FunctionType::Initializer
+        } else {
+            FunctionType::Method
+        };
+        self.function(function_type);
+
+        self.emit_with_constant(OpCode::Method, constant);
+    }
+
+    fn fun_declaration(&mut self) {
+        let global = self.parse_variable("Expect function name.");
+        self.mark_initialized();
+        self.function(FunctionType::Function);
+        self.define_variable(global);
+    }
+
+    fn function(&mut self, function_type: FunctionType) {
+        let name = self.identifier_name(&self.previous.clone().unwrap());
+        self.states.push(FunctionState::new(function_type, name));
+        self.begin_scope();
+
+        self.consume(&TokenType::LeftParen, "Expect '(' after function name.");
+        if !self.check(&TokenType::RightParen) {
+            loop {
+                self.state_mut().function.arity += 1;
+                if self.state().function.arity > u8::MAX as usize {
+                    self.error("Can't have more than 255 parameters.");
+                }
+
+                let constant = self.parse_variable("Expect parameter name.");
+                self.define_variable(constant);
+
+                if !self.r#match(&TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(&TokenType::RightParen, "Expect ')' after parameters.");
+        self.consume(&TokenType::LeftBrace, "Expect '{' before function body.");
+        self.block();
+
+        let (function, upvalues) = self.end_compiler();
+        let function = self.heap.alloc(Obj::Function(function));
+        let constant = self.make_constant(Value::Obj(function));
+        self.emit_with_constant(OpCode::Closure, constant);
+
+        for upvalue in upvalues {
+            self.emit_bytes(upvalue.is_local, upvalue.index);
+        }
     }
 
     fn var_declaration(&mut self) {
@@ -404,23 +987,150 @@ impl Parser {
             "Expect ';' after variable declaration.",
         );
 
-        self.define_variable(&global);
+        self.define_variable(global);
     }
 
-    fn parse_variable(&mut self, error_message: &str) -> String {
+    fn parse_variable(&mut self, error_message: &str) -> usize {
         self.consume(&TokenType::Identifier, error_message);
+
+        self.declare_variable();
+        if self.state().scope_depth > 0 {
+            return 0;
+        }
+
         self.identifier_constant(&self.previous.clone().unwrap())
     }
 
-    fn identifier_constant(&mut self, token: &Token) -> String {
-        if let TokenValue::Identifier(s) = token.value.as_ref().unwrap() {
-            return s.to_string();
+    fn declare_variable(&mut self) {
+        let scope_depth = self.state().scope_depth;
+        if scope_depth == 0 {
+            return;
         }
-        panic!("Not an identifier")
+
+        let name = self.identifier_name(&self.previous.clone().unwrap());
+
+        let already_declared = self
+            .state()
+            .locals
+            .iter()
+            .rev()
+            .take_while(|local| local.depth.is_none_or(|depth| depth >= scope_depth))
+            .any(|local| local.name == name);
+
+        if already_declared {
+            if let Some(diagnostic) =
+                self.error_at_previous("Already a variable with this name in this scope.")
+            {
+                diagnostic.help = Some("shadow it from a nested block instead".to_string());
+            }
+        }
+
+        self.add_local(name);
     }
 
-    fn define_variable(&mut self, name: &str) {
-        self.emit_byte(OpCode::DefineGlobal(name.to_string()));
+    fn add_local(&mut self, name: String) {
+        if self.state().locals.len() == UINT8_COUNT {
+            self.error_at_previous("Too many local variables in function.");
+            return;
+        }
+
+        self.state_mut().locals.push(Local {
+            name,
+            depth: None,
+            is_captured: false,
+        });
+    }
+
+    fn mark_initialized(&mut self) {
+        let state = self.state_mut();
+        if state.scope_depth == 0 {
+            return;
+        }
+
+        if let Some(local) = state.locals.last_mut() {
+            local.depth = Some(state.scope_depth);
+        }
+    }
+
+    fn resolve_local(&mut self, state: usize, name: &str) -> Option<u8> {
+        let (slot, local) = self.states[state]
+            .locals
+            .iter()
+            .enumerate()
+            .rev()
+            .find(|(_, local)| local.name == name)?;
+
+        if local.depth.is_none() {
+            self.error_at_previous("Can't read local variable in its own initializer.");
+        }
+
+        Some(slot as u8)
+    }
+
+    /// Resolves `name` as a variable captured from a function enclosing
+    /// `states[state]`, threading the upvalue through every function in
+    /// between.
+    fn resolve_upvalue(&mut self, state: usize, name: &str) -> Option<u8> {
+        if state == 0 {
+            return None;
+        }
+
+        if let Some(local) = self.resolve_local(state - 1, name) {
+            self.states[state - 1].locals[local as usize].is_captured = true;
+            return Some(self.add_upvalue(state, local, true));
+        }
+
+        if let Some(upvalue) = self.resolve_upvalue(state - 1, name) {
+            return Some(self.add_upvalue(state, upvalue, false));
+        }
+
+        None
+    }
+
+    fn add_upvalue(&mut self, state: usize, index: u8, is_local: bool) -> u8 {
+        let upvalue = UpvalueIndex { is_local, index };
+
+        if let Some(existing) = self.states[state]
+            .upvalues
+            .iter()
+            .position(|candidate| *candidate == upvalue)
+        {
+            return existing as u8;
+        }
+
+        if self.states[state].upvalues.len() == UINT8_COUNT {
+            self.error_at_previous("Too many closure variables in function.");
+            return 0;
+        }
+
+        let state = &mut self.states[state];
+        state.upvalues.push(upvalue);
+        state.function.upvalue_count = state.upvalues.len();
+        (state.upvalues.len() - 1) as u8
+    }
+
+    fn identifier_constant(&mut self, token: &Token) -> usize {
+        let name = self.heap.intern(&self.identifier_name(token));
+        self.make_constant(Value::Obj(name))
+    }
+
+    fn identifier_name(&self, token: &Token) -> String {
+        match (&token.r#type, &token.value) {
+            (_, Some(TokenValue::Identifier(s))) => s.to_string(),
+            (TokenType::This, _) => "this".to_string(),
+            (TokenType::Super, _) => "super".to_string(),
+            // The missing identifier has already been reported by `consume`.
+            _ => String::new(),
+        }
+    }
+
+    fn define_variable(&mut self, global: usize) {
+        if self.state().scope_depth > 0 {
+            self.mark_initialized();
+            return;
+        }
+
+        self.emit_with_constant(OpCode::DefineGlobal, global);
     }
 
     fn r#match(&mut self, token_type: &TokenType) -> bool {
@@ -437,7 +1147,6 @@ impl Parser {
 
     fn print_statement(&mut self) {
         self.expression();
-        dbg!(&self);
         self.consume(&TokenType::Semicolon, "Expect ';' after value.");
         self.emit_byte(OpCode::Print);
     }
@@ -480,40 +1189,165 @@ impl Parser {
         self.consume(&TokenType::RightParen, "Expect ')' after expression.")
     }
 
+    // Operators are emitted at the operator's location rather than after
+    // their operands, so type errors point at the operator itself.
     fn unary(&mut self) {
         let operator_type = self.previous.as_ref().unwrap().r#type.clone();
+        let location = self.previous_location();
 
         self.parse_precedence(Precedence::Unary);
 
         match operator_type {
-            TokenType::Minus => self.emit_byte(OpCode::Negate),
-            TokenType::Bang => self.emit_byte(OpCode::Not),
+            TokenType::Minus => self.emit_byte_at(OpCode::Negate, location),
+            TokenType::Bang => self.emit_byte_at(OpCode::Not, location),
             _ => unreachable!(),
         }
     }
 
     fn binary(&mut self) {
         let operator_type = self.previous.as_ref().unwrap().r#type.clone();
+        let location = self.previous_location();
 
         let rule_precedence = get_rule(&operator_type).precedence + 1;
 
         self.parse_precedence(rule_precedence);
 
-        match operator_type {
-            TokenType::Plus => self.emit_byte(OpCode::Add),
-            TokenType::Minus => self.emit_byte(OpCode::Subtract),
-            TokenType::Star => self.emit_byte(OpCode::Multiply),
-            TokenType::Slash => self.emit_byte(OpCode::Divide),
-            TokenType::BangEqual => self.emit_bytes(&[OpCode::Equal, OpCode::Not]),
-            TokenType::EqualEqual => self.emit_byte(OpCode::Equal),
-            TokenType::Greater => self.emit_byte(OpCode::Greater),
-            TokenType::GreaterEqual => self.emit_bytes(&[OpCode::Less, OpCode::Not]),
-            TokenType::Less => self.emit_byte(OpCode::Less),
-            TokenType::LessEqual => self.emit_bytes(&[OpCode::Greater, OpCode::Not]),
+        let ops: &[OpCode] = match operator_type {
+            TokenType::Plus => &[OpCode::Add],
+            TokenType::Minus => &[OpCode::Subtract],
+            TokenType::Star => &[OpCode::Multiply],
+            TokenType::Slash => &[OpCode::Divide],
+            TokenType::BangEqual => &[OpCode::Equal, OpCode::Not],
+            TokenType::EqualEqual => &[OpCode::Equal],
+            TokenType::Greater => &[OpCode::Greater],
+            TokenType::GreaterEqual => &[OpCode::Less, OpCode::Not],
+            TokenType::Less => &[OpCode::Less],
+            TokenType::LessEqual => &[OpCode::Greater, OpCode::Not],
             _ => unreachable!(),
+        };
+        for &op in ops {
+            self.emit_byte_at(op, location);
         }
     }
 
+    fn and(&mut self) {
+        let end_jump = self.emit_jump(OpCode::JumpIfFalse);
+
+        self.emit_byte(OpCode::Pop);
+        self.parse_precedence(Precedence::And);
+
+        self.patch_jump(end_jump);
+    }
+
+    fn or(&mut self) {
+        let else_jump = self.emit_jump(OpCode::JumpIfFalse);
+        let end_jump = self.emit_jump(OpCode::Jump);
+
+        self.patch_jump(else_jump);
+        self.emit_byte(OpCode::Pop);
+
+        self.parse_precedence(Precedence::Or);
+        self.patch_jump(end_jump);
+    }
+
+    fn call(&mut self) {
+        let arg_count = self.argument_list();
+        self.emit_bytes(OpCode::Call, arg_count);
+    }
+
+    fn argument_list(&mut self) -> u8 {
+        let mut arg_count: usize = 0;
+        if !self.check(&TokenType::RightParen) {
+            loop {
+                self.expression();
+                if arg_count == u8::MAX as usize {
+                    self.error_at_previous("Can't have more than 255 arguments.");
+                }
+                arg_count += 1;
+
+                if !self.r#match(&TokenType::Comma) {
+                    break;
+                }
+            }
+        }
+        self.consume(&TokenType::RightParen, "Expect ')' after arguments.");
+        arg_count as u8
+    }
+
+    fn dot(&mut self, can_assign: bool) {
+        self.consume(&TokenType::Identifier, "Expect property name after '.'.");
+        let name = self.identifier_constant(&self.previous.clone().unwrap());
+
+        if can_assign && self.r#match(&TokenType::Equal) {
+            self.expression();
+            self.emit_with_constant(OpCode::SetProperty, name);
+        } else if self.r#match(&TokenType::LeftParen) {
+            let arg_count = self.argument_list();
+            self.emit_with_constant(OpCode::Invoke, name);
+            self.emit_byte(arg_count);
+        } else {
+            self.emit_with_constant(OpCode::GetProperty, name);
+        }
+    }
+
+    fn subscript(&mut self) {
+        let location = self.previous_location();
+
+        self.expression();
+        self.consume(&TokenType::RightBracket, "Expect ']' after index.");
+        self.emit_byte_at(OpCode::GetIndex, location);
+    }
+
+    fn super_(&mut self) {
+        match self.classes.last() {
+            None => {
+                self.error_at_previous("Can't use 'super' outside of a class.");
+            }
+            Some(class) if !class.has_superclass => {
+                if let Some(diagnostic) =
+                    self.error_at_previous("Can't use 'super' in a class with no superclass.")
+                {
+                    diagnostic.help =
+                        Some("declare a superclass with `class Name < Superclass`".to_string());
+                }
+            }
+            Some(_) => {}
+        }
+
+        self.consume(&TokenType::Dot, "Expect '.' after 'super'.");
+        self.consume(&TokenType::Identifier, "Expect superclass method name.");
+        let name = self.identifier_constant(&self.previous.clone().unwrap());
+
+        let this_token = Token {
+            r#type: TokenType::This,
+            ..Default::default()
+        };
+        let super_token = Token {
+            r#type: TokenType::Super,
+            ..Default::default()
+        };
+
+        self.named_variable(&this_token, false);
+        if self.r#match(&TokenType::LeftParen) {
+            let arg_count = self.argument_list();
+            self.named_variable(&super_token, false);
+            self.emit_with_constant(OpCode::SuperInvoke, name);
+            self.emit_byte(arg_count);
+        } else {
+            self.named_variable(&super_token, false);
+            self.emit_with_constant(OpCode::GetSuper, name);
+        }
+    }
+
+    fn this(&mut self) {
+        if self.classes.is_empty() {
+            self.error_at_previous("Can't use 'this' outside of a class.");
+            return;
+        }
+
+        self.variable(false);
+    }
+
     fn literal(&mut self) {
         let operator_type = self.previous.as_ref().unwrap().r#type.clone();
         match operator_type {
@@ -529,7 +1363,8 @@ impl Parser {
             if TokenType::String == x.r#type {
                 let value = x.value.as_ref().unwrap();
                 if let TokenValue::String(s) = value {
-                    self.emit_constant(Value::Obj(Obj::String(s.to_string())))
+                    let string = self.heap.intern(s);
+                    self.emit_constant(Value::Obj(string))
                 }
             }
         }
@@ -540,13 +1375,27 @@ impl Parser {
     }
 
     fn named_variable(&mut self, name: &Token, can_assign: bool) {
-        let var_name = self.identifier_constant(name);
-        if can_assign && self.r#match(&TokenType::Equal) {
+        let var_name = self.identifier_name(name);
+
+        let state = self.states.len() - 1;
+        let (get_op, set_op, arg) = if let Some(slot) = self.resolve_local(state, &var_name) {
+            (OpCode::GetLocal, OpCode::SetLocal, slot as usize)
+        } else if let Some(slot) = self.resolve_upvalue(state, &var_name) {
+            (OpCode::GetUpvalue, OpCode::SetUpvalue, slot as usize)
+        } else {
+            let constant = self.identifier_constant(name);
+            (OpCode::GetGlobal, OpCode::SetGlobal, constant)
+        };
+
+        // Slots always fit a byte; only a global's name constant can need
+        // the long form.
+        let op = if can_assign && self.r#match(&TokenType::Equal) {
             self.expression();
-            self.emit_byte(OpCode::SetGlobal(var_name));
+            set_op
         } else {
-            self.emit_byte(OpCode::GetGlobal(var_name));
-        }
+            get_op
+        };
+        self.emit_with_constant(op, arg);
     }
 
     fn expression(&mut self) {
@@ -564,22 +1413,33 @@ impl Parser {
             PrefixRule::Number => self.number(),
             PrefixRule::Literal => self.literal(),
             PrefixRule::String => self.string(),
-            PrefixRule::None => self.error("Expected expression"),
+            PrefixRule::None => {
+                self.error_at_previous("Expect expression.");
+                return;
+            }
             PrefixRule::Variable => self.variable(can_assign),
+            PrefixRule::This => self.this(),
+            PrefixRule::Super => self.super_(),
         }
 
         while precedence <= get_rule(&self.current.as_ref().unwrap().r#type).precedence {
             self.advance();
             let infix_rule = get_rule(&self.previous.as_ref().unwrap().r#type).infix;
 
-            if can_assign && self.r#match(&TokenType::Equal) {
-                self.error("Invalid assignment target.");
-            }
             match infix_rule {
                 InfixRule::Binary => self.binary(),
+                InfixRule::And => self.and(),
+                InfixRule::Or => self.or(),
+                InfixRule::Call => self.call(),
+                InfixRule::Dot => self.dot(can_assign),
+                InfixRule::Subscript => self.subscript(),
                 InfixRule::None => unreachable!(),
             }
         }
+
+        if can_assign && self.r#match(&TokenType::Equal) {
+            self.error_at_previous("Invalid assignment target.");
+        }
     }
 }
 
@@ -587,35 +1447,221 @@ impl Parser {
 mod tests {
     use super::*;
 
-    fn test_compiler(source: &str) -> Result<Vec<OpCode>, VMError> {
-        let mut compiler = Compiler::new();
-        compiler.compile(source)
+    fn test_compiler(source: &str) -> Result<(Chunk, Heap), Vec<Diagnostic>> {
+        let mut heap = Heap::new();
+        let function = Compiler::new().compile(source, &mut heap)?;
+        Ok((heap.function(function).chunk.clone(), heap))
     }
 
     macro_rules! test_compiler {
         ($test_name:ident, $source:expr) => {
             #[test]
             fn $test_name() {
-                let tokens = test_compiler($source).unwrap();
+                let (chunk, heap) = test_compiler($source).unwrap();
 
-                insta::assert_yaml_snapshot!(tokens);
+                insta::assert_snapshot!(disassemble_chunk(&chunk, "script", &heap));
             }
         };
     }
 
-    test_compiler!(unary_minus, "-10.23");
-    test_compiler!(math, "10.23 - 30 * -20");
-    test_compiler!(precedence, "10 + 20 * 30");
-    test_compiler!(grouping, "(10 + 20) * 30");
-    test_compiler!(gte_false, "10 >= 20");
-    test_compiler!(gte_true, "20 >= 10");
-    test_compiler!(gte_same, "10 >= 10");
-    test_compiler!(lte_false, "20 <= 10");
-    test_compiler!(lte_true, "20 <= 10");
-    test_compiler!(lte_same, "10 <= 10");
-    test_compiler!(ee_true, "10 == 10");
-    test_compiler!(ee_false, "10 == 20");
-    test_compiler!(ne_true, "10 != 10");
-    test_compiler!(ne_false, "10 != 20");
-    test_compiler!(string_concat, "\"hello\" + \"world\" + \"from\" + \"rust\"");
+    test_compiler!(unary_minus, "-10.23;");
+    test_compiler!(math, "10.23 - 30 * -20;");
+    test_compiler!(precedence, "10 + 20 * 30;");
+    test_compiler!(grouping, "(10 + 20) * 30;");
+    test_compiler!(gte_false, "10 >= 20;");
+    test_compiler!(gte_true, "20 >= 10;");
+    test_compiler!(gte_same, "10 >= 10;");
+    test_compiler!(lte_false, "20 <= 10;");
+    test_compiler!(lte_true, "20 <= 10;");
+    test_compiler!(lte_same, "10 <= 10;");
+    test_compiler!(ee_true, "10 == 10;");
+    test_compiler!(ee_false, "10 == 20;");
+    test_compiler!(ne_true, "10 != 10;");
+    test_compiler!(ne_false, "10 != 20;");
+    test_compiler!(
+        string_concat,
+        "\"hello\" + \"world\" + \"from\" + \"rust\";"
+    );
+    test_compiler!(block_locals, "{ var a = 1; var b = a; b = a + 2; }");
+    test_compiler!(
+        nested_block_shadowing,
+        "var a = 1; { var a = 2; { var a = 3; print a; } print a; }"
+    );
+
+    test_compiler!(if_statement, "if (true) print 1;");
+    test_compiler!(
+        if_else_statement,
+        "var a = 1; if (a > 2) { print a; } else { print 2; }"
+    );
+
+    test_compiler!(while_statement, "var i = 0; while (i < 3) i = i + 1;");
+    test_compiler!(for_statement, "for (var i = 0; i < 3; i = i + 1) print i;");
+    test_compiler!(for_statement_no_clauses, "for (;;) print 1;");
+
+    test_compiler!(and, "true and false;");
+    test_compiler!(or, "nil or \"default\";");
+    test_compiler!(and_or_precedence, "1 or 2 and 3;");
+
+    test_compiler!(
+        fun_declaration,
+        "fun add(a, b) { return a + b; } print add(1, 2);"
+    );
+    test_compiler!(fun_implicit_return, "fun noop() {} noop();");
+    test_compiler!(
+        local_fun_declaration,
+        "{ fun double(n) { return n * 2; } print double(2); }"
+    );
+
+    test_compiler!(
+        closure_captures_local,
+        "fun outer() { var x = 1; fun inner() { x = x + 1; return x; } return inner; }"
+    );
+    test_compiler!(
+        closure_captures_through_nesting,
+        "fun a() { var x = 1; fun b() { fun c() { print x; } c(); } b(); }"
+    );
+    test_compiler!(closure_in_block, "{ var x = 1; fun f() { print x; } }");
+
+    test_compiler!(class_declaration, "class Point {} var p = Point();");
+    test_compiler!(
+        property_access,
+        "class Box {} var b = Box(); b.value = 1; print b.value;"
+    );
+    test_compiler!(chained_property_set, "a.b.c = 1;");
+
+    test_compiler!(
+        class_methods,
+        "class Counter { init(start) { this.count = start; } bump() { this.count = this.count + 1; return this; } }"
+    );
+    test_compiler!(method_invoke, "var c = Counter(1); c.bump().bump();");
+    test_compiler!(
+        this_captured_in_closure,
+        "class A { method() { fun inner() { return this; } return inner; } }"
+    );
+
+    test_compiler!(
+        subclass,
+        "class A { method() { print 1; } } class B < A { method() { super.method(); } }"
+    );
+    test_compiler!(
+        super_method_access,
+        "class A { f() {} } class B < A { g() { return super.f; } }"
+    );
+    test_compiler!(
+        shared_constants,
+        "var a = 1; var b = 1; print \"a\" + \"a\";"
+    );
+    test_compiler!(subscripts, "print args()[0];");
+
+    #[test]
+    fn repl_mode_returns_final_expression() {
+        let mut heap = Heap::new();
+        let mut compiler = Compiler::new();
+        let mut disassemble = |source: &str| {
+            let function = compiler.compile_repl(source, &mut heap).unwrap();
+            disassemble_chunk(&heap.function(function).chunk, "script", &heap)
+        };
+
+        insta::assert_snapshot!(disassemble("var a = 1;\na + 2"));
+        assert_eq!(
+            disassemble("var a = 1;\na + 2;"),
+            disassemble("var a = 1;\na + 2")
+        );
+    }
+
+    #[test]
+    fn repl_mode_only_returns_the_last_statement() {
+        let mut heap = Heap::new();
+        assert!(Compiler::new().compile_repl("1 + 2 3", &mut heap).is_err());
+        assert!(Compiler::new().compile("1 + 2", &mut heap).is_err());
+    }
+
+    #[test]
+    fn constant_long() {
+        let source: String = (0..300).map(|n| format!("{};", n)).collect();
+        let (chunk, _) = test_compiler(&source).unwrap();
+
+        assert_eq!(chunk.constants.len(), 300);
+        assert_eq!(chunk.opcode(0), Ok(OpCode::Constant));
+        assert_eq!(chunk.opcode(256 * 3), Ok(OpCode::ConstantLong));
+        assert_eq!(chunk.read_u24(256 * 3 + 1), 256);
+    }
+
+    #[test]
+    fn long_name_and_closure_operands() {
+        let literals: String = (0..300).map(|n| format!("{};", n)).collect();
+        let source = literals
+            + "var late = 1; late = late; fun f() {} \
+               class C < C2 { m() { super.m(); return super.m; } } C().x = C().m(C().y);";
+        let (chunk, heap) = test_compiler(&source).unwrap();
+        let disassembly = disassemble_chunk(&chunk, "script", &heap);
+
+        for op in [
+            OpCode::DefineGlobalLong,
+            OpCode::GetGlobalLong,
+            OpCode::SetGlobalLong,
+            OpCode::ClosureLong,
+            OpCode::ClassLong,
+            OpCode::MethodLong,
+            OpCode::GetPropertyLong,
+            OpCode::SetPropertyLong,
+            OpCode::InvokeLong,
+        ] {
+            assert!(disassembly.contains(&format!("{} ", op)), "no {}", op);
+        }
+        assert!(disassembly.contains("OP_DEFINE_GLOBAL_LONG  300 'late'"));
+    }
+
+    #[test]
+    fn collects_every_error() {
+        let diagnostics =
+            test_compiler("var 1 = 2;\nprint (;\nvar ok = 3;\n{ var a; var a; }").unwrap_err();
+
+        insta::assert_yaml_snapshot!(diagnostics);
+    }
+
+    #[test]
+    fn inherit_from_self() {
+        insta::assert_yaml_snapshot!(test_compiler("class A < A {}").unwrap_err());
+    }
+
+    #[test]
+    fn super_outside_class() {
+        insta::assert_yaml_snapshot!(test_compiler("super.method();").unwrap_err());
+    }
+
+    #[test]
+    fn super_without_superclass() {
+        insta::assert_yaml_snapshot!(test_compiler("class A { f() { super.f(); } }").unwrap_err());
+    }
+
+    #[test]
+    fn this_outside_class() {
+        insta::assert_yaml_snapshot!(test_compiler("print this;").unwrap_err());
+    }
+
+    #[test]
+    fn return_value_from_initializer() {
+        insta::assert_yaml_snapshot!(test_compiler("class A { init() { return 1; } }").unwrap_err());
+    }
+
+    #[test]
+    fn redeclare_local_in_same_scope() {
+        insta::assert_yaml_snapshot!(test_compiler("{ var a = 1; var a = 2; }").unwrap_err());
+    }
+
+    #[test]
+    fn local_in_own_initializer() {
+        insta::assert_yaml_snapshot!(test_compiler("{ var a = 1; { var a = a; } }").unwrap_err());
+    }
+
+    #[test]
+    fn invalid_assignment_target() {
+        assert!(test_compiler("var a = 1; var b = 2; a + b = 3;").is_err());
+    }
+
+    #[test]
+    fn return_from_top_level() {
+        assert!(test_compiler("return 1;").is_err());
+    }
 }
@@ -0,0 +1,206 @@
+use std::{
+    fmt::{self, Write},
+    ops::Range,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::chunk::Location;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Severity {
+    Error,
+    Warning,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "Error"),
+            Severity::Warning => write!(f, "Warning"),
+        }
+    }
+}
+
+/// A problem found while compiling, pointing at the source it concerns.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Diagnostic {
+    pub severity: Severity,
+    pub message: String,
+    /// Byte offsets of the offending text within the source.
+    pub span: Range<usize>,
+    /// Where `span` starts.
+    pub location: Location,
+    pub notes: Vec<String>,
+    pub help: Option<String>,
+}
+
+impl Diagnostic {
+    pub fn error(message: impl Into<String>, span: Range<usize>, location: Location) -> Self {
+        Self {
+            severity: Severity::Error,
+            message: message.into(),
+            span,
+            location,
+            notes: vec![],
+            help: None,
+        }
+    }
+}
+
+const BOLD: &str = "1";
+const BLUE: &str = "1;34";
+
+impl Diagnostic {
+    /// Renders the diagnostic rustc-style: a header, the offending line of
+    /// `source` with the span underlined, then any notes and help. `name`
+    /// identifies the source in the `-->` line. With `color` set, the output
+    /// is highlighted with ANSI escapes.
+    pub fn render(&self, name: &str, source: &str, color: bool) -> String {
+        let mut out = String::new();
+        self.write_rendered(&mut out, name, source, color)
+            .expect("writing to a String cannot fail");
+        out
+    }
+
+    fn write_rendered(
+        &self,
+        out: &mut String,
+        name: &str,
+        source: &str,
+        color: bool,
+    ) -> fmt::Result {
+        let paint = |style: &str, text: &str| {
+            if color {
+                format!("\x1b[{}m{}\x1b[0m", style, text)
+            } else {
+                text.to_string()
+            }
+        };
+        let level = match self.severity {
+            Severity::Error => "1;31",
+            Severity::Warning => "1;33",
+        };
+
+        let Location { line, column } = self.location;
+        let line_number = line.to_string();
+        let gutter = " ".repeat(line_number.len());
+        let text = source.lines().nth(line.saturating_sub(1)).unwrap_or("");
+
+        // Keep tabs in the padding so the carets line up with the source.
+        let padding: String = text
+            .chars()
+            .take(column.saturating_sub(1))
+            .map(|c| if c == '\t' { '\t' } else { ' ' })
+            .collect();
+        let width = source
+            .get(self.span.clone())
+            .map_or(0, |spanned| {
+                spanned.chars().take_while(|&c| c != '\n').count()
+            })
+            .max(1);
+
+        writeln!(
+            out,
+            "{}{}",
+            paint(level, &self.severity.to_string().to_lowercase()),
+            paint(BOLD, &format!(": {}", self.message))
+        )?;
+        writeln!(
+            out,
+            "{}{} {}:{}:{}",
+            gutter,
+            paint(BLUE, "-->"),
+            name,
+            line,
+            column
+        )?;
+        writeln!(out, "{} {}", gutter, paint(BLUE, "|"))?;
+        writeln!(
+            out,
+            "{} {} {}",
+            paint(BLUE, &line_number),
+            paint(BLUE, "|"),
+            text
+        )?;
+        writeln!(
+            out,
+            "{} {} {}{}",
+            gutter,
+            paint(BLUE, "|"),
+            padding,
+            paint(level, &"^".repeat(width))
+        )?;
+        for note in &self.notes {
+            writeln!(
+                out,
+                "{} {} {}: {}",
+                gutter,
+                paint(BLUE, "="),
+                paint(BOLD, "note"),
+                note
+            )?;
+        }
+        if let Some(help) = &self.help {
+            writeln!(
+                out,
+                "{} {} {}: {}",
+                gutter,
+                paint(BLUE, "="),
+                paint(BOLD, "help"),
+                help
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Diagnostic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[line {}, column {}] {}: {}",
+            self.location.line, self.location.column, self.severity, self.message
+        )?;
+        for note in &self.notes {
+            write!(f, "\n  = note: {}", note)?;
+        }
+        if let Some(help) = &self.help {
+            write!(f, "\n  = help: {}", help)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{compiler::Compiler, heap::Heap};
+
+    fn render(source: &str) -> String {
+        let diagnostics = Compiler::new()
+            .compile(source, &mut Heap::new())
+            .unwrap_err();
+        diagnostics
+            .iter()
+            .map(|diagnostic| diagnostic.render("test.lox", source, false))
+            .collect::<Vec<_>>()
+            .join("\n")
+    }
+
+    #[test]
+    fn render_missing_expression() {
+        insta::assert_snapshot!(render("var a = 1;\nprint a + ;"));
+    }
+
+    #[test]
+    fn render_with_notes_and_help() {
+        insta::assert_snapshot!(render(
+            "class A {\n\tinit() {\n\t\treturn \"nope\";\n\t}\n}"
+        ));
+    }
+
+    #[test]
+    fn render_multiple() {
+        insta::assert_snapshot!(render("var 1;\n{ var b; var b; }\nprint \"unterminated"));
+    }
+}
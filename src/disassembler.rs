@@ -0,0 +1,159 @@
+use std::fmt::Write;
+
+use crate::{chunk::Chunk, heap::Heap, opcode::OpCode, value::Obj};
+
+/// Renders every instruction in `chunk` under a `== name ==` header, followed
+/// by the chunks of any functions it defines. Constants are looked up in
+/// `heap`.
+pub fn disassemble_chunk(chunk: &Chunk, name: &str, heap: &Heap) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "== {} ==", name);
+
+    let mut offset = 0;
+    while offset < chunk.len() {
+        offset = disassemble_instruction(chunk, offset, heap, &mut out);
+    }
+
+    for &constant in &chunk.constants {
+        if let Some(Obj::Function(function)) = heap.object(constant) {
+            out.push('\n');
+            out.push_str(&disassemble_chunk(
+                &function.chunk,
+                &function.to_string(),
+                heap,
+            ));
+        }
+    }
+    out
+}
+
+/// Appends the instruction at `offset` to `out` as one line (or several, for
+/// `Closure`) and returns the offset of the next instruction.
+pub fn disassemble_instruction(
+    chunk: &Chunk,
+    offset: usize,
+    heap: &Heap,
+    out: &mut String,
+) -> usize {
+    let _ = write!(out, "{:04} ", offset);
+
+    let line = chunk.line(offset);
+    if offset > 0 && line == chunk.line(offset - 1) {
+        out.push_str("   | ");
+    } else {
+        let _ = write!(out, "{:4} ", line);
+    }
+
+    let op = match chunk.opcode(offset) {
+        Ok(op) => op,
+        Err(byte) => {
+            let _ = writeln!(out, "Unknown opcode {}", byte);
+            return offset + 1;
+        }
+    };
+
+    match op {
+        OpCode::Constant
+        | OpCode::ConstantLong
+        | OpCode::DefineGlobal
+        | OpCode::DefineGlobalLong
+        | OpCode::GetGlobal
+        | OpCode::GetGlobalLong
+        | OpCode::SetGlobal
+        | OpCode::SetGlobalLong
+        | OpCode::Class
+        | OpCode::ClassLong
+        | OpCode::GetProperty
+        | OpCode::GetPropertyLong
+        | OpCode::SetProperty
+        | OpCode::SetPropertyLong
+        | OpCode::Method
+        | OpCode::MethodLong
+        | OpCode::GetSuper
+        | OpCode::GetSuperLong => {
+            let (constant, offset) = constant_operand(op, chunk, offset);
+            constant_instruction(op, chunk, constant, heap, out);
+            offset
+        }
+        OpCode::GetLocal
+        | OpCode::SetLocal
+        | OpCode::GetUpvalue
+        | OpCode::SetUpvalue
+        | OpCode::Call => {
+            let _ = writeln!(out, "{:<16} {:4}", op.to_string(), chunk.code[offset + 1]);
+            offset + 2
+        }
+        OpCode::Jump | OpCode::JumpIfFalse => jump_instruction(op, 1, chunk, offset, out),
+        OpCode::Loop => jump_instruction(op, -1, chunk, offset, out),
+        OpCode::Invoke | OpCode::InvokeLong | OpCode::SuperInvoke | OpCode::SuperInvokeLong => {
+            let (constant, offset) = constant_operand(op, chunk, offset);
+            let arg_count = chunk.code[offset];
+            let _ = writeln!(
+                out,
+                "{:<16} ({} args) {:4} '{}'",
+                op.to_string(),
+                arg_count,
+                constant,
+                chunk.constants[constant].display(heap)
+            );
+            offset + 1
+        }
+        OpCode::Closure | OpCode::ClosureLong => {
+            let (constant, mut offset) = constant_operand(op, chunk, offset);
+            constant_instruction(op, chunk, constant, heap, out);
+
+            if let Some(Obj::Function(function)) = heap.object(chunk.constants[constant]) {
+                for _ in 0..function.upvalue_count {
+                    let is_local = chunk.code[offset];
+                    let index = chunk.code[offset + 1];
+                    let _ = writeln!(
+                        out,
+                        "{:04}    |                     {} {}",
+                        offset,
+                        if is_local == 1 { "local" } else { "upvalue" },
+                        index
+                    );
+                    offset += 2;
+                }
+            }
+            offset
+        }
+        _ => {
+            let _ = writeln!(out, "{}", op);
+            offset + 1
+        }
+    }
+}
+
+/// Reads the constant index following the opcode at `offset`. Returns it
+/// along with the offset just past it.
+fn constant_operand(op: OpCode, chunk: &Chunk, offset: usize) -> (usize, usize) {
+    if op.is_long() {
+        (chunk.read_u24(offset + 1), offset + 4)
+    } else {
+        (chunk.code[offset + 1] as usize, offset + 2)
+    }
+}
+
+fn constant_instruction(op: OpCode, chunk: &Chunk, constant: usize, heap: &Heap, out: &mut String) {
+    let _ = writeln!(
+        out,
+        "{:<16} {:4} '{}'",
+        op.to_string(),
+        constant,
+        chunk.constants[constant].display(heap)
+    );
+}
+
+fn jump_instruction(
+    op: OpCode,
+    sign: isize,
+    chunk: &Chunk,
+    offset: usize,
+    out: &mut String,
+) -> usize {
+    let jump = chunk.read_u16(offset + 1) as isize;
+    let target = offset as isize + 3 + sign * jump;
+    let _ = writeln!(out, "{:<16} {:4} -> {}", op.to_string(), offset, target);
+    offset + 3
+}
@@ -0,0 +1,200 @@
+//! The source formatter behind `bytelox fmt`. It works on the token stream
+//! and only ever changes whitespace: one statement per line, blocks indented
+//! two spaces, binary operators spaced, and comments and single blank lines
+//! between statements kept where they were.
+
+use crate::{
+    compiler::Compiler,
+    diagnostic::Diagnostic,
+    heap::Heap,
+    scanner::{Scanner, Token, TokenType},
+};
+
+const INDENT: &str = "  ";
+
+/// Reformats `source`. A script that doesn't compile is left alone and its
+/// diagnostics returned, since the layout rules rely on well-formed code.
+pub fn format(source: &str) -> Result<String, Vec<Diagnostic>> {
+    Compiler::new().compile(source, &mut Heap::new())?;
+
+    let mut scanner = Scanner::new();
+    scanner.input(source);
+
+    let mut formatter = Formatter::new(source);
+    loop {
+        let token = scanner.scan_token();
+        if token.r#type == TokenType::Eof {
+            break;
+        }
+        formatter.token(&token);
+    }
+    Ok(formatter.finish())
+}
+
+struct Formatter {
+    chars: Vec<char>,
+    out: String,
+    indent: usize,
+    /// How many parentheses are open, so the semicolons between `for`
+    /// clauses don't end the line.
+    parens: usize,
+    /// The offset just past the previous token. The gap up to the next one
+    /// tells whether the source had a blank line or a trailing comment.
+    end: usize,
+    previous: Option<TokenType>,
+    /// Whether the previous token was a prefix `-` or `!`.
+    previous_unary: bool,
+    /// Whether the next token starts a new line.
+    line_ended: bool,
+}
+
+impl Formatter {
+    fn new(source: &str) -> Self {
+        Self {
+            chars: source.chars().collect(),
+            out: String::new(),
+            indent: 0,
+            parens: 0,
+            end: 0,
+            previous: None,
+            previous_unary: false,
+            line_ended: false,
+        }
+    }
+
+    fn token(&mut self, token: &Token) {
+        let newlines = self.chars[self.end..token.start]
+            .iter()
+            .filter(|&&c| c == '\n')
+            .count();
+        let text: String = self.chars[token.start..token.start + token.length]
+            .iter()
+            .collect();
+        self.end = token.start + token.length;
+
+        let kind = &token.r#type;
+        let previous = self.previous.replace(kind.clone());
+        if *kind == TokenType::RightBrace {
+            self.indent -= 1;
+        }
+
+        let empty_block = previous == Some(TokenType::LeftBrace) && *kind == TokenType::RightBrace;
+        let after_brace = previous == Some(TokenType::RightBrace)
+            && matches!(
+                kind,
+                TokenType::Else | TokenType::Semicolon | TokenType::Comma | TokenType::RightParen
+            );
+        let trailing_comment = *kind == TokenType::Comment && newlines == 0;
+
+        if self.line_ended && !(empty_block || after_brace || trailing_comment) {
+            self.out.push('\n');
+            if newlines > 1
+                && previous != Some(TokenType::LeftBrace)
+                && *kind != TokenType::RightBrace
+            {
+                self.out.push('\n');
+            }
+            self.out.push_str(&INDENT.repeat(self.indent));
+        } else if previous.is_some() && self.needs_space(previous.as_ref(), kind) {
+            self.out.push(' ');
+        }
+        self.out.push_str(&text);
+
+        self.previous_unary = match kind {
+            TokenType::Bang => true,
+            TokenType::Minus => !matches!(
+                previous,
+                Some(
+                    TokenType::Identifier
+                        | TokenType::Number
+                        | TokenType::String
+                        | TokenType::RightParen
+                        | TokenType::RightBracket
+                        | TokenType::This
+                        | TokenType::True
+                        | TokenType::False
+                        | TokenType::Nil
+                )
+            ),
+            _ => false,
+        };
+
+        match kind {
+            TokenType::LeftParen => self.parens += 1,
+            TokenType::RightParen => self.parens -= 1,
+            TokenType::LeftBrace => self.indent += 1,
+            _ => {}
+        }
+
+        self.line_ended = match kind {
+            TokenType::LeftBrace | TokenType::RightBrace | TokenType::Comment => true,
+            TokenType::Semicolon => self.parens == 0,
+            _ => false,
+        };
+    }
+
+    fn needs_space(&self, previous: Option<&TokenType>, next: &TokenType) -> bool {
+        match (previous, next) {
+            (_, TokenType::Comment) => true,
+            (
+                _,
+                TokenType::RightParen
+                | TokenType::RightBracket
+                | TokenType::Comma
+                | TokenType::Semicolon
+                | TokenType::Dot,
+            ) => false,
+            (Some(TokenType::LeftParen | TokenType::LeftBracket | TokenType::Dot), _) => false,
+            (Some(TokenType::LeftBrace), TokenType::RightBrace) => false,
+            _ if self.previous_unary => false,
+            // Calls, the parameter list of a declaration, and subscripts.
+            (
+                Some(
+                    TokenType::Identifier
+                    | TokenType::RightParen
+                    | TokenType::RightBracket
+                    | TokenType::This
+                    | TokenType::Super,
+                ),
+                TokenType::LeftParen | TokenType::LeftBracket,
+            ) => false,
+            _ => true,
+        }
+    }
+
+    fn finish(mut self) -> String {
+        if !self.out.is_empty() {
+            self.out.push('\n');
+        }
+        self.out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn formats_messy_source() {
+        let source = "// counters\nfun makeCounter(){var count=0;fun increment(){count=count+1;return count;}return increment;}\n\n\n\nvar c=makeCounter();print c( ) ;// 1\nclass A<B{init(x){this.x=-x;}}\nif(!c()){print \"no\";}else print \"yes\";\nfor(var i=0;i<3;i=i+1)print i*-1;\nfor(;;){}\nvar first=args( ) [ 0 ];print -len(args())[0];";
+
+        insta::assert_snapshot!(format(source).unwrap());
+    }
+
+    #[test]
+    fn formatting_is_idempotent() {
+        for entry in fs::read_dir("tests").unwrap() {
+            let source = fs::read_to_string(entry.unwrap().path()).unwrap();
+            let formatted = format(&source).unwrap();
+
+            assert_eq!(format(&formatted).unwrap(), formatted);
+        }
+    }
+
+    #[test]
+    fn refuses_invalid_source() {
+        assert!(format("print (;").is_err());
+    }
+}
@@ -0,0 +1,367 @@
+//! The managed object heap and its mark-sweep collector.
+//!
+//! Objects live in slots addressed by [`Handle`]s, so values can refer to
+//! the same object from many places and objects can refer to each other in
+//! cycles. The heap never collects on its own: the VM calls
+//! [`Heap::collect`] between instructions, when every live object is
+//! reachable from a root it can enumerate. The compiler's in-progress
+//! functions, which are only reachable from the compiler itself, are
+//! therefore never swept: compilation finishes before the next collection,
+//! and the VM roots the finished script on its stack before running it.
+
+use std::mem;
+
+use crate::{
+    chunk::LineRun,
+    interner::{hash_str, Interner, LoxString},
+    value::{BoundMethod, Class, Closure, Function, Instance, List, Native, Obj, Upvalue, Value},
+};
+
+/// The number of bytes allocated before the first collection.
+const INITIAL_NEXT_GC: usize = 1024 * 1024;
+
+/// After a collection, the next one happens once the heap has grown by this
+/// factor over what survived.
+const GC_HEAP_GROW_FACTOR: usize = 2;
+
+/// A reference to an object in a [`Heap`]. Handles compare by identity.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Handle(u32);
+
+impl Handle {
+    fn index(self) -> usize {
+        self.0 as usize
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Slot {
+    obj: Obj,
+    marked: bool,
+    /// The bytes counted against `bytes_allocated` for this object.
+    size: usize,
+}
+
+macro_rules! accessor {
+    ($name:ident, $variant:ident, $ty:ty) => {
+        pub fn $name(&self, handle: Handle) -> &$ty {
+            match self.get(handle) {
+                Obj::$variant(obj) => obj,
+                obj => panic!(
+                    concat!("expected ", stringify!($variant), ", found {:?}"),
+                    obj
+                ),
+            }
+        }
+    };
+    (mut $name:ident, $variant:ident, $ty:ty) => {
+        pub fn $name(&mut self, handle: Handle) -> &mut $ty {
+            match self.get_mut(handle) {
+                Obj::$variant(obj) => obj,
+                obj => panic!(
+                    concat!("expected ", stringify!($variant), ", found {:?}"),
+                    obj
+                ),
+            }
+        }
+    };
+}
+
+#[derive(Debug, Clone)]
+pub struct Heap {
+    slots: Vec<Option<Slot>>,
+    /// Indices of empty slots, reused before the heap grows.
+    free: Vec<u32>,
+    strings: Interner,
+    /// Objects marked reachable whose references haven't been traced yet.
+    gray: Vec<Handle>,
+    bytes_allocated: usize,
+    next_gc: usize,
+    /// When set, [`Heap::should_collect`] always returns true, so a missing
+    /// root frees a live object as soon as possible instead of rarely.
+    pub stress: bool,
+}
+
+impl Default for Heap {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Heap {
+    pub fn new() -> Self {
+        Self {
+            slots: vec![],
+            free: vec![],
+            strings: Interner::new(),
+            gray: vec![],
+            bytes_allocated: 0,
+            next_gc: INITIAL_NEXT_GC,
+            stress: false,
+        }
+    }
+
+    pub fn alloc(&mut self, obj: Obj) -> Handle {
+        let size = obj.size();
+        self.bytes_allocated += size;
+
+        let slot = Some(Slot {
+            obj,
+            marked: false,
+            size,
+        });
+        match self.free.pop() {
+            Some(index) => {
+                self.slots[index as usize] = slot;
+                Handle(index)
+            }
+            None => {
+                self.slots.push(slot);
+                Handle((self.slots.len() - 1) as u32)
+            }
+        }
+    }
+
+    /// Returns the canonical string with the contents `chars`, allocating it
+    /// if it isn't already interned.
+    pub fn intern(&mut self, chars: &str) -> Handle {
+        let hash = hash_str(chars);
+        if let Some(string) = self
+            .strings
+            .find(hash, |handle| self.string(handle).as_str() == chars)
+        {
+            return string;
+        }
+
+        let string = self.alloc(Obj::String(LoxString::new(chars)));
+        self.strings.insert(hash, string);
+        string
+    }
+
+    pub fn get(&self, handle: Handle) -> &Obj {
+        match &self.slots[handle.index()] {
+            Some(slot) => &slot.obj,
+            None => panic!("{:?} was used after being freed", handle),
+        }
+    }
+
+    pub fn get_mut(&mut self, handle: Handle) -> &mut Obj {
+        match &mut self.slots[handle.index()] {
+            Some(slot) => &mut slot.obj,
+            None => panic!("{:?} was used after being freed", handle),
+        }
+    }
+
+    /// Returns the object `value` refers to, if it is one.
+    pub fn object(&self, value: Value) -> Option<&Obj> {
+        match value {
+            Value::Obj(handle) => Some(self.get(handle)),
+            _ => None,
+        }
+    }
+
+    accessor!(string, String, LoxString);
+    accessor!(function, Function, Function);
+    accessor!(closure, Closure, Closure);
+    accessor!(upvalue, Upvalue, Upvalue);
+    accessor!(mut upvalue_mut, Upvalue, Upvalue);
+    accessor!(class, Class, Class);
+    accessor!(mut class_mut, Class, Class);
+    accessor!(instance, Instance, Instance);
+    accessor!(mut instance_mut, Instance, Instance);
+    accessor!(bound_method, BoundMethod, BoundMethod);
+    accessor!(native, Native, Native);
+    accessor!(list, List, List);
+
+    /// The approximate number of bytes held by live (or not yet swept)
+    /// objects.
+    pub fn bytes_allocated(&self) -> usize {
+        self.bytes_allocated
+    }
+
+    /// The number of objects currently allocated.
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn should_collect(&self) -> bool {
+        self.stress || self.bytes_allocated > self.next_gc
+    }
+
+    /// Frees every object not reachable from `roots`, then sets the next
+    /// threshold relative to what survived.
+    pub fn collect(&mut self, roots: impl IntoIterator<Item = Value>) {
+        for root in roots {
+            if let Value::Obj(handle) = root {
+                self.gray.push(handle);
+            }
+        }
+        self.trace_references();
+        self.sweep();
+
+        self.next_gc = (self.bytes_allocated * GC_HEAP_GROW_FACTOR).max(INITIAL_NEXT_GC);
+    }
+
+    fn trace_references(&mut self) {
+        while let Some(handle) = self.gray.pop() {
+            let Some(slot) = &mut self.slots[handle.index()] else {
+                panic!("{:?} was reachable after being freed", handle);
+            };
+            if !mem::replace(&mut slot.marked, true) {
+                slot.obj.trace(&mut self.gray);
+            }
+        }
+    }
+
+    fn sweep(&mut self) {
+        let slots = &self.slots;
+        self.strings
+            .retain(|handle| matches!(&slots[handle.index()], Some(slot) if slot.marked));
+
+        for (index, entry) in self.slots.iter_mut().enumerate() {
+            match entry {
+                Some(slot) if slot.marked => slot.marked = false,
+                Some(slot) => {
+                    self.bytes_allocated -= slot.size;
+                    *entry = None;
+                    self.free.push(index as u32);
+                }
+                None => {}
+            }
+        }
+    }
+}
+
+impl Obj {
+    /// The bytes this object is counted as when it is allocated. Tables that
+    /// grow afterwards, like instance fields, aren't tracked.
+    fn size(&self) -> usize {
+        mem::size_of::<Slot>()
+            + match self {
+                Obj::String(string) => string.chars.len(),
+                Obj::Function(function) => {
+                    function.chunk.code.len()
+                        + function.chunk.constants.len() * mem::size_of::<Value>()
+                        + function.chunk.lines.len() * mem::size_of::<LineRun>()
+                }
+                Obj::Closure(closure) => closure.upvalues.len() * mem::size_of::<Handle>(),
+                Obj::List(list) => list.items.len() * mem::size_of::<Value>(),
+                _ => 0,
+            }
+    }
+
+    /// Pushes every object this one refers to onto `gray`.
+    fn trace(&self, gray: &mut Vec<Handle>) {
+        let mut mark = |value: Value| {
+            if let Value::Obj(handle) = value {
+                gray.push(handle);
+            }
+        };
+
+        match self {
+            Obj::String(_) | Obj::Native(_) | Obj::Upvalue(Upvalue::Open(_)) => {}
+            Obj::Function(Function { chunk, .. }) => {
+                chunk.constants.iter().copied().for_each(&mut mark)
+            }
+            Obj::Closure(Closure { function, upvalues }) => {
+                mark(Value::Obj(*function));
+                upvalues
+                    .iter()
+                    .for_each(|&upvalue| mark(Value::Obj(upvalue)));
+            }
+            Obj::Upvalue(Upvalue::Closed(value)) => mark(*value),
+            Obj::Class(Class { name, methods }) => {
+                mark(Value::Obj(*name));
+                for (&name, &method) in methods {
+                    mark(Value::Obj(name));
+                    mark(Value::Obj(method));
+                }
+            }
+            Obj::Instance(Instance { class, fields }) => {
+                mark(Value::Obj(*class));
+                for (&name, &value) in fields {
+                    mark(Value::Obj(name));
+                    mark(value);
+                }
+            }
+            Obj::BoundMethod(BoundMethod { receiver, method }) => {
+                mark(*receiver);
+                mark(Value::Obj(*method));
+            }
+            Obj::List(List { items }) => items.iter().copied().for_each(&mut mark),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn equal_strings_share_a_handle() {
+        let mut heap = Heap::new();
+        let a = heap.intern("hello");
+        let b = heap.intern(&format!("hel{}", "lo"));
+
+        assert_eq!(a, b);
+        assert_ne!(a, heap.intern("world"));
+    }
+
+    #[test]
+    fn collects_unreachable_objects() {
+        let mut heap = Heap::new();
+        let kept = heap.intern("kept");
+        heap.intern("garbage");
+
+        heap.collect([Value::Obj(kept)]);
+
+        assert_eq!(heap.len(), 1);
+        assert_eq!(heap.string(kept).as_str(), "kept");
+        assert_eq!(
+            heap.bytes_allocated(),
+            Obj::String(LoxString::new("kept")).size()
+        );
+    }
+
+    #[test]
+    fn swept_strings_are_reinterned() {
+        let mut heap = Heap::new();
+        heap.intern("temporary");
+        heap.collect([]);
+
+        assert!(heap.is_empty());
+        let string = heap.intern("temporary");
+        assert_eq!(heap.string(string).as_str(), "temporary");
+    }
+
+    #[test]
+    fn collects_cycles() {
+        let mut heap = Heap::new();
+        let name = heap.intern("Node");
+        let class = heap.alloc(Obj::Class(Class::new(name)));
+        let a = heap.alloc(Obj::Instance(Instance::new(class)));
+        let b = heap.alloc(Obj::Instance(Instance::new(class)));
+        let next = heap.intern("next");
+        heap.instance_mut(a).fields.insert(next, Value::Obj(b));
+        heap.instance_mut(b).fields.insert(next, Value::Obj(a));
+
+        heap.collect([Value::Obj(a)]);
+        assert_eq!(heap.len(), 5);
+
+        heap.collect([]);
+        assert!(heap.is_empty());
+    }
+
+    #[test]
+    fn reuses_freed_slots() {
+        let mut heap = Heap::new();
+        let garbage = heap.intern("garbage");
+        heap.collect([]);
+
+        assert_eq!(heap.intern("new"), garbage);
+    }
+}
@@ -0,0 +1,104 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    hash::{BuildHasherDefault, Hasher},
+};
+
+use crate::heap::Handle;
+
+/// An immutable string. Strings allocated through
+/// [`Heap::intern`](crate::heap::Heap::intern) are unique, so two of them are
+/// equal exactly when they have the same handle, and the interner only hashes
+/// a string's contents when looking it up.
+#[derive(Debug, Clone)]
+pub struct LoxString {
+    pub chars: Box<str>,
+}
+
+impl LoxString {
+    pub fn new(chars: &str) -> Self {
+        Self {
+            chars: chars.into(),
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.chars
+    }
+}
+
+impl fmt::Display for LoxString {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.chars)
+    }
+}
+
+/// FNV-1a, as used by clox.
+pub fn hash_str(chars: &str) -> u32 {
+    chars.bytes().fold(2166136261, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(16777619)
+    })
+}
+
+/// A hasher that passes through a precomputed `u32` hash instead of hashing
+/// the key's contents again.
+#[derive(Default)]
+pub struct PrehashedHasher(u64);
+
+impl Hasher for PrehashedHasher {
+    fn finish(&self) -> u64 {
+        self.0
+    }
+
+    fn write(&mut self, bytes: &[u8]) {
+        for &byte in bytes {
+            self.0 = (self.0 ^ byte as u64).wrapping_mul(0x100000001b3);
+        }
+    }
+
+    /// Spreads `hash` into the high bits too, which `HashMap` uses to pick
+    /// control bytes.
+    fn write_u32(&mut self, hash: u32) {
+        self.0 = (hash as u64).wrapping_mul(0x9e3779b97f4a7c15);
+    }
+}
+
+/// A map keyed by interned strings. Each string has exactly one handle, so
+/// the handle's index serves as the hash.
+pub type StringMap<V> = HashMap<Handle, V, BuildHasherDefault<PrehashedHasher>>;
+
+/// The table of every live string, so each distinct string is allocated once.
+/// Strings are bucketed by the [`hash_str`] of their contents, which the
+/// caller computes once per lookup and reuses to insert. Entries don't keep their strings alive: the heap drops the ones it is about
+/// to sweep.
+#[derive(Default, Debug, Clone)]
+pub struct Interner {
+    strings: HashMap<u32, Vec<Handle>, BuildHasherDefault<PrehashedHasher>>,
+}
+
+impl Interner {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the interned string with hash `hash` that `matches` accepts.
+    pub fn find(&self, hash: u32, matches: impl Fn(Handle) -> bool) -> Option<Handle> {
+        self.strings
+            .get(&hash)?
+            .iter()
+            .copied()
+            .find(|&handle| matches(handle))
+    }
+
+    pub fn insert(&mut self, hash: u32, string: Handle) {
+        self.strings.entry(hash).or_default().push(string);
+    }
+
+    /// Forgets every string `is_live` rejects.
+    pub fn retain(&mut self, is_live: impl Fn(Handle) -> bool) {
+        self.strings.retain(|_, bucket| {
+            bucket.retain(|&handle| is_live(handle));
+            !bucket.is_empty()
+        });
+    }
+}
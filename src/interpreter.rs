@@ -1,46 +1,423 @@
-use std::{fs::read_to_string, io, process};
+use std::{
+    fs,
+    io::{self, Read, Write},
+    process,
+};
 
-use crate::vm::{VMError, VM};
+use crate::{
+    compiler::Compiler,
+    disassembler::disassemble_chunk,
+    formatter,
+    heap::{Handle, Heap},
+    loxc,
+    repl::Repl,
+    vm::{VMError, VM},
+};
+
+// Exit statuses from BSD's sysexits.h.
+const EX_USAGE: i32 = 64;
+const EX_DATAERR: i32 = 65;
+const EX_SOFTWARE: i32 = 70;
+const EX_IOERR: i32 = 74;
+
+/// The path that stands for standard input.
+const STDIN: &str = "-";
+const STDIN_NAME: &str = "<stdin>";
+const INLINE_NAME: &str = "-e";
+
+const USAGE: &str = "\
+Usage: bytelox [options] [script [args...]]
+       bytelox [options] <command> [args...]
+
+Commands:
+  run <script|-> [args...]    Run a script, or a .loxc file
+  repl                        Start the interactive prompt (the default)
+  check <script>...           Compile scripts without running them
+  disasm <script>             Print the bytecode of a script or .loxc file
+  compile <script> -o <out>   Compile a script to a .loxc file
+  fmt <script|->...           Reformat scripts in place, or stdin to stdout
+
+Options:
+  -e <code> [args...]         Run <code> instead of a script
+  --trace                     Print each instruction as it runs
+  --stress-gc                 Collect garbage before every instruction
+  -h, --help                  Print this message
+";
+
+/// Where the code to run comes from.
+#[derive(Debug, PartialEq)]
+enum Source {
+    /// A script or `.loxc` file, or standard input for `-`.
+    Path(String),
+    /// Code passed with `-e`.
+    Inline(String),
+}
+
+#[derive(Debug, PartialEq)]
+enum Command {
+    Run { source: Source, args: Vec<String> },
+    Repl,
+    Check(Vec<String>),
+    Disassemble(String),
+    Compile { input: String, output: String },
+    Format(Vec<String>),
+    Help,
+}
+
+#[derive(Debug, PartialEq)]
+struct Invocation {
+    trace: bool,
+    stress_gc: bool,
+    command: Command,
+}
 
 pub struct Interpreter {
     args: Vec<String>,
+    trace: bool,
+    stress_gc: bool,
 }
 
 impl Interpreter {
     pub fn new(args: Vec<String>) -> Self {
-        Self { args }
+        Self {
+            args,
+            trace: false,
+            stress_gc: false,
+        }
     }
 
+    /// Runs the command the arguments ask for and exits with its status if
+    /// it failed.
     pub fn run(&mut self) {
-        match self.args.len() {
-            1 => self.repl(),
-            2 => self.run_file(self.args[1].to_string()).unwrap(),
-            _ => {
-                eprintln!("Usage: bytelox [path]");
-                process::exit(64);
+        let invocation = match parse(self.args.get(1..).unwrap_or_default()) {
+            Ok(invocation) => invocation,
+            Err(message) => {
+                eprintln!("error: {}\n\n{}", message, USAGE);
+                process::exit(EX_USAGE);
             }
+        };
+        self.trace = invocation.trace;
+        self.stress_gc = invocation.stress_gc;
+
+        let result = match invocation.command {
+            Command::Run { source, args } => self.run_source(source, args),
+            Command::Repl => self.repl(),
+            Command::Check(paths) => paths
+                .iter()
+                .map(|path| self.check_file(path))
+                .fold(Ok(()), Result::and),
+            Command::Disassemble(path) => self.disassemble_file(&path),
+            Command::Compile { input, output } => self.compile_file(&input, &output),
+            Command::Format(paths) => paths
+                .iter()
+                .map(|path| self.format_file(path))
+                .fold(Ok(()), Result::and),
+            Command::Help => {
+                print!("{}", USAGE);
+                Ok(())
+            }
+        };
+
+        if let Err(status) = result {
+            let _ = io::stdout().flush();
+            process::exit(status);
         }
     }
 
-    fn repl(&mut self) {
-        let mut line = String::new();
+    fn vm(&self) -> VM {
         let mut vm = VM::new();
+        vm.debug = self.trace;
+        vm.compiler.set_debug(self.trace);
+        vm.heap.stress = self.stress_gc;
+        vm
+    }
+
+    fn repl(&self) -> Result<(), i32> {
+        match Repl::new(self.vm()).run() {
+            Ok(0) => Ok(()),
+            Ok(status) => Err(status),
+            Err(error) => {
+                eprintln!("error: {}", error);
+                Err(EX_IOERR)
+            }
+        }
+    }
 
-        loop {
-            print!("> ");
+    fn run_source(&self, source: Source, args: Vec<String>) -> Result<(), i32> {
+        let mut vm = self.vm();
+        vm.args = args;
 
-            if let Err(e) = io::stdin().read_line(&mut line) {
-                eprintln!("{}", e);
-                break;
+        let (name, source) = match source {
+            Source::Inline(code) => (INLINE_NAME.to_string(), code),
+            Source::Path(path) => {
+                let bytes = read(&path)?;
+
+                // Precompiled files carry no source, so errors can't be
+                // rendered against it.
+                if loxc::is_bytecode(&bytes) {
+                    let function = load(&bytes, &path, &mut vm.heap)?;
+                    return match vm.interpret_function(function) {
+                        Ok(_) => Ok(()),
+                        Err(error) => exit_status(error, |error| eprintln!("error: {}", error)),
+                    };
+                }
+                (display_name(&path), decode(bytes, &path)?)
             }
+        };
 
-            let _ = vm.interpret(&line);
+        match vm.interpret(&source) {
+            Ok(()) => Ok(()),
+            Err(error) => exit_status(error, |error| error.report(&name, &source)),
         }
     }
 
-    fn run_file(&mut self, path: String) -> Result<(), VMError> {
-        let source = read_to_string(path).unwrap();
-        let mut vm = VM::new();
-        vm.interpret(&source)
+    fn check_file(&self, path: &str) -> Result<(), i32> {
+        let source = decode(read(path)?, path)?;
+        compile(&source, path, &mut Heap::new()).map(drop)
+    }
+
+    fn disassemble_file(&self, path: &str) -> Result<(), i32> {
+        let bytes = read(path)?;
+
+        let mut heap = Heap::new();
+        let function = if loxc::is_bytecode(&bytes) {
+            load(&bytes, path, &mut heap)?
+        } else {
+            compile(&decode(bytes, path)?, path, &mut heap)?
+        };
+        print!(
+            "{}",
+            disassemble_chunk(&heap.function(function).chunk, &display_name(path), &heap)
+        );
+        Ok(())
+    }
+
+    fn compile_file(&self, input: &str, output: &str) -> Result<(), i32> {
+        let source = decode(read(input)?, input)?;
+
+        let mut heap = Heap::new();
+        let function = compile(&source, input, &mut heap)?;
+        fs::write(output, loxc::serialize(function, &heap)).map_err(|error| {
+            eprintln!("error: {}: {}", output, error);
+            EX_IOERR
+        })
+    }
+
+    /// Rewrites `path` in place if formatting changes it. Standard input is
+    /// formatted to standard output instead.
+    fn format_file(&self, path: &str) -> Result<(), i32> {
+        let source = decode(read(path)?, path)?;
+
+        let formatted = formatter::format(&source).map_err(|diagnostics| {
+            VMError::CompileTime(diagnostics).report(&display_name(path), &source);
+            EX_DATAERR
+        })?;
+
+        let written = if path == STDIN {
+            io::stdout().write_all(formatted.as_bytes())
+        } else if formatted != source {
+            fs::write(path, formatted)
+        } else {
+            Ok(())
+        };
+        written.map_err(|error| {
+            eprintln!("error: {}: {}", display_name(path), error);
+            EX_IOERR
+        })
+    }
+}
+
+/// Parses the arguments after the program name. Options are only recognized
+/// before the command or script; everything after a script is passed to it.
+fn parse(args: &[String]) -> Result<Invocation, String> {
+    let mut trace = false;
+    let mut stress_gc = false;
+
+    let mut args = args;
+    while let [option, rest @ ..] = args {
+        match option.as_str() {
+            "--trace" => trace = true,
+            "--stress-gc" => stress_gc = true,
+            _ => break,
+        }
+        args = rest;
+    }
+
+    let Some((first, rest)) = args.split_first() else {
+        return Ok(Invocation {
+            trace,
+            stress_gc,
+            command: Command::Repl,
+        });
+    };
+    let command = match (first.as_str(), rest) {
+        ("-h" | "--help" | "help", _) => Command::Help,
+        ("-e", [code, args @ ..]) => Command::Run {
+            source: Source::Inline(code.clone()),
+            args: args.to_vec(),
+        },
+        ("run", [path, args @ ..]) => Command::Run {
+            source: Source::Path(path.clone()),
+            args: args.to_vec(),
+        },
+        ("repl", []) => Command::Repl,
+        ("check", paths) if !paths.is_empty() => Command::Check(paths.to_vec()),
+        ("disasm" | "--disassemble", [path]) => Command::Disassemble(path.clone()),
+        ("compile", [input, flag, output]) if flag == "-o" => Command::Compile {
+            input: input.clone(),
+            output: output.clone(),
+        },
+        ("fmt", paths) if !paths.is_empty() => Command::Format(paths.to_vec()),
+        ("-e" | "run" | "repl" | "check" | "disasm" | "--disassemble" | "compile" | "fmt", _) => {
+            return Err(format!("wrong arguments for '{}'", first))
+        }
+        (option, _) if option.starts_with('-') && option != STDIN => {
+            return Err(format!("unknown option '{}'", option))
+        }
+        (path, args) => Command::Run {
+            source: Source::Path(path.to_string()),
+            args: args.to_vec(),
+        },
+    };
+
+    Ok(Invocation {
+        trace,
+        stress_gc,
+        command,
+    })
+}
+
+/// Turns a failed run into the process's exit status, reporting it first
+/// unless the script simply called `exit()`.
+fn exit_status(error: VMError, report: impl FnOnce(&VMError)) -> Result<(), i32> {
+    let status = match error {
+        VMError::Exit(0) => return Ok(()),
+        VMError::Exit(status) => return Err(status),
+        VMError::CompileTime(_) => EX_DATAERR,
+        VMError::Runtime(_) => EX_SOFTWARE,
+    };
+    report(&error);
+    Err(status)
+}
+
+/// Reads a whole file, or standard input for `-`.
+fn read(path: &str) -> Result<Vec<u8>, i32> {
+    let mut bytes = vec![];
+    let result = if path == STDIN {
+        io::stdin().read_to_end(&mut bytes).map(drop)
+    } else {
+        fs::read(path).map(|contents| bytes = contents)
+    };
+
+    result.map(|()| bytes).map_err(|error| {
+        eprintln!("error: {}: {}", display_name(path), error);
+        EX_IOERR
+    })
+}
+
+fn decode(bytes: Vec<u8>, path: &str) -> Result<String, i32> {
+    String::from_utf8(bytes).map_err(|_| {
+        eprintln!("error: {}: not valid UTF-8", display_name(path));
+        EX_DATAERR
+    })
+}
+
+fn compile(source: &str, path: &str, heap: &mut Heap) -> Result<Handle, i32> {
+    Compiler::new()
+        .compile(source, heap)
+        .map_err(|diagnostics| {
+            VMError::CompileTime(diagnostics).report(&display_name(path), source);
+            EX_DATAERR
+        })
+}
+
+fn load(bytes: &[u8], path: &str, heap: &mut Heap) -> Result<Handle, i32> {
+    loxc::deserialize(bytes, heap).map_err(|error| {
+        eprintln!("error: {}: {}", display_name(path), error);
+        EX_DATAERR
+    })
+}
+
+fn display_name(path: &str) -> String {
+    if path == STDIN {
+        STDIN_NAME.to_string()
+    } else {
+        path.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse_args(args: &str) -> Result<Invocation, String> {
+        let args: Vec<String> = args.split_whitespace().map(str::to_string).collect();
+        parse(&args)
+    }
+
+    fn command(args: &str) -> Command {
+        parse_args(args).unwrap().command
+    }
+
+    fn run(source: Source, args: &[&str]) -> Command {
+        Command::Run {
+            source,
+            args: args.iter().map(|arg| arg.to_string()).collect(),
+        }
+    }
+
+    #[test]
+    fn no_arguments_start_the_repl() {
+        assert_eq!(command(""), Command::Repl);
+        assert_eq!(command("repl"), Command::Repl);
+    }
+
+    #[test]
+    fn scripts_receive_the_arguments_after_them() {
+        let expected = run(Source::Path("main.lox".to_string()), &["a", "--trace"]);
+
+        assert_eq!(command("main.lox a --trace"), expected);
+        assert_eq!(command("run main.lox a --trace"), expected);
+        assert_eq!(
+            command("-e print(1); a"),
+            run(Source::Inline("print(1);".to_string()), &["a"])
+        );
+        assert_eq!(command("run -"), run(Source::Path("-".to_string()), &[]));
+    }
+
+    #[test]
+    fn options_come_before_the_command() {
+        let invocation = parse_args("--stress-gc --trace check a.lox b.lox").unwrap();
+
+        assert!(invocation.trace && invocation.stress_gc);
+        assert_eq!(
+            invocation.command,
+            Command::Check(vec!["a.lox".to_string(), "b.lox".to_string()])
+        );
+    }
+
+    #[test]
+    fn parses_tool_commands() {
+        assert_eq!(
+            command("compile in.lox -o out.loxc"),
+            Command::Compile {
+                input: "in.lox".to_string(),
+                output: "out.loxc".to_string()
+            }
+        );
+        assert_eq!(
+            command("--disassemble a.lox"),
+            Command::Disassemble("a.lox".to_string())
+        );
+        assert_eq!(command("fmt -"), Command::Format(vec!["-".to_string()]));
+        assert_eq!(command("-h"), Command::Help);
+    }
+
+    #[test]
+    fn rejects_malformed_commands() {
+        assert!(parse_args("compile in.lox").is_err());
+        assert!(parse_args("check").is_err());
+        assert!(parse_args("-e").is_err());
+        assert!(parse_args("--verbose main.lox").is_err());
     }
 }
@@ -1,6 +1,15 @@
+pub mod chunk;
 pub mod compiler;
+pub mod diagnostic;
+pub mod disassembler;
+pub mod formatter;
+pub mod heap;
+pub mod interner;
 pub mod interpreter;
+pub mod loxc;
+pub mod native;
 pub mod opcode;
+pub mod repl;
 pub mod scanner;
 pub mod value;
 pub mod vm;
@@ -0,0 +1,464 @@
+//! The `.loxc` precompiled script format: a fixed header followed by the
+//! bincode-encoded top-level function.
+//!
+//! | bytes | contents                                  |
+//! |-------|-------------------------------------------|
+//! | 0..4  | magic, `b"LOXC"`                          |
+//! | 4..6  | format version, little-endian `u16`       |
+//! | 6..10 | CRC-32 of the payload, little-endian `u32` |
+//! | 10..  | payload                                   |
+//!
+//! A payload that decodes is still verified before it's loaded, since the
+//! checksum doesn't stop a hand-made file from pointing anywhere.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    chunk::{Chunk, LineRun},
+    heap::{Handle, Heap},
+    opcode::OpCode,
+    value::{Function, Obj, Value},
+};
+
+pub const MAGIC: &[u8; 4] = b"LOXC";
+
+/// Bumped whenever the opcode set, operand encoding or serialized layout of
+/// `FunctionData` changes, so stale files are rejected instead of misread.
+pub const FORMAT_VERSION: u16 = 5;
+
+const HEADER_LEN: usize = 10;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LoadError {
+    /// The file doesn't start with the `.loxc` magic bytes.
+    NotBytecode,
+    UnsupportedVersion(u16),
+    ChecksumMismatch,
+    Corrupt(String),
+}
+
+impl fmt::Display for LoadError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            LoadError::NotBytecode => write!(f, "not a bytelox bytecode file"),
+            LoadError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported bytecode format version {} (expected {})",
+                version, FORMAT_VERSION
+            ),
+            LoadError::ChecksumMismatch => write!(f, "bytecode checksum mismatch"),
+            LoadError::Corrupt(message) => write!(f, "corrupt bytecode: {}", message),
+        }
+    }
+}
+
+/// The serialized form of a [`Function`]. Constants hold heap handles, which
+/// mean nothing outside the heap that issued them, so they are written out
+/// by value instead.
+#[derive(Debug, Serialize, Deserialize)]
+struct FunctionData {
+    arity: usize,
+    upvalue_count: usize,
+    name: String,
+    code: Vec<u8>,
+    lines: Vec<LineRun>,
+    constants: Vec<Constant>,
+}
+
+impl fmt::Display for FunctionData {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.name.is_empty() {
+            f.write_str("<script>")
+        } else {
+            write!(f, "<fn {}>", self.name)
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+enum Constant {
+    Number(f64),
+    String(String),
+    Function(FunctionData),
+}
+
+impl FunctionData {
+    fn new(function: &Function, heap: &Heap) -> Self {
+        let constants = function
+            .chunk
+            .constants
+            .iter()
+            .map(|&constant| match constant {
+                Value::Number(number) => Constant::Number(number),
+                Value::Obj(handle) => match heap.get(handle) {
+                    Obj::String(string) => Constant::String(string.as_str().to_string()),
+                    Obj::Function(function) => {
+                        Constant::Function(FunctionData::new(function, heap))
+                    }
+                    obj => unreachable!("{:?} can't be a constant", obj),
+                },
+                value => unreachable!("{:?} can't be a constant", value),
+            })
+            .collect();
+
+        Self {
+            arity: function.arity,
+            upvalue_count: function.upvalue_count,
+            name: function.name.clone(),
+            code: function.chunk.code.clone(),
+            lines: function.chunk.lines.clone(),
+            constants,
+        }
+    }
+
+    /// Checks that the code is made of whole, known instructions whose
+    /// constants, jump targets and upvalues all exist, along with those of
+    /// every nested function. The checksum only catches accidental damage,
+    /// and the VM and disassembler trust the code they're given.
+    fn verify(&self) -> Result<(), LoadError> {
+        let code = &self.code;
+        let corrupt = |offset: usize, message: String| {
+            LoadError::Corrupt(format!("{} at offset {} in {}", message, offset, self))
+        };
+
+        let mut starts = vec![false; code.len()];
+        let mut jumps = Vec::new();
+        let mut last = None;
+        let mut offset = 0;
+        while offset < code.len() {
+            starts[offset] = true;
+            let op = OpCode::try_from(code[offset])
+                .map_err(|byte| corrupt(offset, format!("unknown opcode {}", byte)))?;
+            let mut next = offset + 1;
+            let operand = |next: &mut usize, len: usize| {
+                let bytes = code
+                    .get(*next..*next + len)
+                    .ok_or_else(|| corrupt(offset, format!("truncated {}", op)))?;
+                *next += len;
+                Ok(bytes
+                    .iter()
+                    .fold(0, |value, &byte| value << 8 | byte as usize))
+            };
+
+            match op {
+                OpCode::GetLocal | OpCode::SetLocal | OpCode::Call => {
+                    operand(&mut next, 1)?;
+                }
+                OpCode::GetUpvalue | OpCode::SetUpvalue => {
+                    let index = operand(&mut next, 1)?;
+                    if index >= self.upvalue_count {
+                        return Err(corrupt(offset, format!("upvalue {} out of range", index)));
+                    }
+                }
+                OpCode::Jump | OpCode::JumpIfFalse | OpCode::Loop => {
+                    let jump = operand(&mut next, 2)?;
+                    let target = if op == OpCode::Loop {
+                        next.checked_sub(jump)
+                    } else {
+                        Some(next + jump)
+                    };
+                    jumps.push((offset, target));
+                }
+                _ if op.long().is_some() || op.is_long() => {
+                    let index = operand(&mut next, if op.is_long() { 3 } else { 1 })?;
+                    let constant = self.constants.get(index).ok_or_else(|| {
+                        corrupt(offset, format!("constant {} out of range", index))
+                    })?;
+
+                    match (op, constant) {
+                        (OpCode::Constant | OpCode::ConstantLong, Constant::Function(_)) => {
+                            return Err(corrupt(
+                                offset,
+                                format!("constant {} is a function", index),
+                            ))
+                        }
+                        (OpCode::Constant | OpCode::ConstantLong, _) => {}
+                        (OpCode::Closure | OpCode::ClosureLong, Constant::Function(function)) => {
+                            for _ in 0..function.upvalue_count {
+                                let is_local = operand(&mut next, 1)?;
+                                let index = operand(&mut next, 1)?;
+                                if is_local > 1 || (is_local == 0 && index >= self.upvalue_count) {
+                                    return Err(corrupt(
+                                        offset,
+                                        format!("invalid upvalue pair ({}, {})", is_local, index),
+                                    ));
+                                }
+                            }
+                        }
+                        (OpCode::Closure | OpCode::ClosureLong, _) => {
+                            return Err(corrupt(
+                                offset,
+                                format!("constant {} isn't a function", index),
+                            ))
+                        }
+                        (_, Constant::String(_)) => {}
+                        _ => {
+                            return Err(corrupt(offset, format!("constant {} isn't a name", index)))
+                        }
+                    }
+
+                    if matches!(
+                        op,
+                        OpCode::Invoke
+                            | OpCode::InvokeLong
+                            | OpCode::SuperInvoke
+                            | OpCode::SuperInvokeLong
+                    ) {
+                        operand(&mut next, 1)?;
+                    }
+                }
+                _ => {}
+            }
+
+            last = Some(op);
+            offset = next;
+        }
+
+        for (offset, target) in jumps {
+            if target.is_none_or(|target| starts.get(target) != Some(&true)) {
+                return Err(corrupt(offset, "jump target out of range".to_string()));
+            }
+        }
+        if last != Some(OpCode::Return) {
+            return Err(corrupt(
+                code.len(),
+                "code doesn't end in a return".to_string(),
+            ));
+        }
+
+        for constant in &self.constants {
+            if let Constant::Function(function) = constant {
+                function.verify()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Allocates the function, its strings and its nested functions in
+    /// `heap`.
+    fn load(self, heap: &mut Heap) -> Handle {
+        let constants = self
+            .constants
+            .into_iter()
+            .map(|constant| match constant {
+                Constant::Number(number) => Value::Number(number),
+                Constant::String(string) => Value::Obj(heap.intern(&string)),
+                Constant::Function(function) => Value::Obj(function.load(heap)),
+            })
+            .collect();
+
+        heap.alloc(Obj::Function(Function {
+            arity: self.arity,
+            upvalue_count: self.upvalue_count,
+            name: self.name,
+            chunk: Chunk::from_parts(self.code, constants, self.lines),
+            source: 0,
+        }))
+    }
+}
+
+/// Returns true if `bytes` look like a `.loxc` file rather than source.
+pub fn is_bytecode(bytes: &[u8]) -> bool {
+    bytes.starts_with(MAGIC)
+}
+
+pub fn serialize(function: Handle, heap: &Heap) -> Vec<u8> {
+    let data = FunctionData::new(heap.function(function), heap);
+    let payload = bincode::serialize(&data).expect("functions are always serializable");
+
+    let mut bytes = Vec::with_capacity(HEADER_LEN + payload.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&FORMAT_VERSION.to_le_bytes());
+    bytes.extend_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+    bytes.extend_from_slice(&payload);
+    bytes
+}
+
+/// Loads the top-level function in `bytes` into `heap`.
+pub fn deserialize(bytes: &[u8], heap: &mut Heap) -> Result<Handle, LoadError> {
+    if !is_bytecode(bytes) {
+        return Err(LoadError::NotBytecode);
+    }
+    if bytes.len() < HEADER_LEN {
+        return Err(LoadError::Corrupt("truncated header".to_string()));
+    }
+
+    let version = u16::from_le_bytes([bytes[4], bytes[5]]);
+    if version != FORMAT_VERSION {
+        return Err(LoadError::UnsupportedVersion(version));
+    }
+
+    let checksum = u32::from_le_bytes([bytes[6], bytes[7], bytes[8], bytes[9]]);
+    let payload = &bytes[HEADER_LEN..];
+    if crc32fast::hash(payload) != checksum {
+        return Err(LoadError::ChecksumMismatch);
+    }
+
+    let data: FunctionData =
+        bincode::deserialize(payload).map_err(|error| LoadError::Corrupt(error.to_string()))?;
+    data.verify()?;
+    Ok(data.load(heap))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{compiler::Compiler, disassembler::disassemble_chunk};
+
+    fn compiled() -> Vec<u8> {
+        let mut heap = Heap::new();
+        let function = Compiler::new()
+            .compile(
+                "fun add(a, b) { return a + b; } print add(\"a\", \"b\");",
+                &mut heap,
+            )
+            .unwrap();
+        serialize(function, &heap)
+    }
+
+    #[test]
+    fn round_trip() {
+        let bytes = compiled();
+        let mut heap = Heap::new();
+        let function = deserialize(&bytes, &mut heap).unwrap();
+
+        let disassembly = disassemble_chunk(&heap.function(function).chunk, "script", &heap);
+        assert!(disassembly.contains("'<fn add>'"));
+        assert!(disassembly.contains("'a'"));
+        assert_eq!(serialize(function, &heap), bytes);
+    }
+
+    #[test]
+    fn rejects_source() {
+        assert_eq!(
+            deserialize(b"print 1;", &mut Heap::new()),
+            Err(LoadError::NotBytecode)
+        );
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        let mut bytes = compiled();
+        bytes[4..6].copy_from_slice(&(FORMAT_VERSION + 1).to_le_bytes());
+
+        assert_eq!(
+            deserialize(&bytes, &mut Heap::new()),
+            Err(LoadError::UnsupportedVersion(FORMAT_VERSION + 1))
+        );
+    }
+
+    /// Compiles `source`, lets `edit` change the function, and writes it back
+    /// out with a fresh checksum, as a hand-crafted file would be.
+    fn tampered(source: &str, edit: impl FnOnce(&mut FunctionData)) -> Vec<u8> {
+        let mut heap = Heap::new();
+        let function = Compiler::new().compile(source, &mut heap).unwrap();
+        let mut data = FunctionData::new(heap.function(function), &heap);
+        edit(&mut data);
+
+        let payload = bincode::serialize(&data).unwrap();
+        let mut bytes = serialize(function, &heap)[..HEADER_LEN].to_vec();
+        bytes[6..10].copy_from_slice(&crc32fast::hash(&payload).to_le_bytes());
+        bytes.extend_from_slice(&payload);
+        bytes
+    }
+
+    fn corruption(source: &str, edit: impl FnOnce(&mut FunctionData)) -> String {
+        match deserialize(&tampered(source, edit), &mut Heap::new()) {
+            Err(LoadError::Corrupt(message)) => message,
+            result => panic!("expected corrupt bytecode, got {:?}", result),
+        }
+    }
+
+    #[test]
+    fn accepts_untampered_code() {
+        let bytes = tampered(
+            "class A { m() { return this; } } class B < A {} \
+             fun f(x) { fun g() { return x; } while (x) x = x - 1; return g; }",
+            |_| {},
+        );
+        assert!(deserialize(&bytes, &mut Heap::new()).is_ok());
+    }
+
+    #[test]
+    fn rejects_unknown_opcodes() {
+        assert_eq!(
+            corruption("print 1;", |data| data.code[0] = 0xff),
+            "unknown opcode 255 at offset 0 in <script>"
+        );
+    }
+
+    #[test]
+    fn rejects_truncated_operands() {
+        assert_eq!(
+            corruption("print 1;", |data| data.code.truncate(1)),
+            "truncated OP_CONSTANT at offset 0 in <script>"
+        );
+    }
+
+    #[test]
+    fn rejects_constants_out_of_range() {
+        assert_eq!(
+            corruption("print 1;", |data| data.code[1] = 200),
+            "constant 200 out of range at offset 0 in <script>"
+        );
+    }
+
+    #[test]
+    fn rejects_constants_of_the_wrong_kind() {
+        assert_eq!(
+            corruption("print a;", |data| data.constants[0] = Constant::Number(1.0)),
+            "constant 0 isn't a name at offset 0 in <script>"
+        );
+        assert_eq!(
+            corruption("fun f() {}", |data| data.constants[1] =
+                Constant::Number(1.0)),
+            "constant 1 isn't a function at offset 0 in <script>"
+        );
+    }
+
+    #[test]
+    fn rejects_jumps_out_of_range() {
+        assert_eq!(
+            corruption("if (true) print 1;", |data| {
+                data.code[2..4].copy_from_slice(&0xffffu16.to_be_bytes())
+            }),
+            "jump target out of range at offset 1 in <script>"
+        );
+    }
+
+    fn nested(data: &mut FunctionData, index: usize) -> &mut FunctionData {
+        match &mut data.constants[index] {
+            Constant::Function(function) => function,
+            constant => panic!("{:?} isn't a function", constant),
+        }
+    }
+
+    #[test]
+    fn rejects_mismatched_upvalues() {
+        let source = "fun f() { var x; fun g() { return x; } }";
+
+        assert_eq!(
+            corruption(source, |data| nested(nested(data, 1), 0).upvalue_count =
+                100),
+            "invalid upvalue pair (6, 5) at offset 1 in <fn f>"
+        );
+        assert_eq!(
+            corruption(source, |data| nested(nested(data, 1), 0).code[1] = 1),
+            "upvalue 1 out of range at offset 0 in <fn g>"
+        );
+    }
+
+    #[test]
+    fn rejects_corrupted_payload() {
+        let mut bytes = compiled();
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xff;
+
+        assert_eq!(
+            deserialize(&bytes, &mut Heap::new()),
+            Err(LoadError::ChecksumMismatch)
+        );
+    }
+}
@@ -0,0 +1,84 @@
+use std::{
+    env,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    value::{List, Obj, Value},
+    vm::{RuntimeError, RuntimeErrorKind, VM},
+};
+
+/// Returns the number of seconds since the Unix epoch.
+pub fn clock(_vm: &mut VM, _args: &[Value]) -> Result<Value, RuntimeError> {
+    let elapsed = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_err(|error| RuntimeError::new(RuntimeErrorKind::Native, error.to_string()))?;
+
+    Ok(Value::from(elapsed.as_secs_f64()))
+}
+
+/// Returns the number of characters in a string or elements in a list.
+pub fn len(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    let len = match vm.heap.object(args[0]) {
+        Some(Obj::String(string)) => string.as_str().chars().count(),
+        Some(Obj::List(list)) => list.items.len(),
+        _ => {
+            return Err(RuntimeError::new(
+                RuntimeErrorKind::TypeError,
+                "Argument to 'len' must be a string or list.",
+            ))
+        }
+    };
+
+    Ok(Value::from(len as f64))
+}
+
+/// Returns a list of the command-line arguments after the script name.
+pub fn args(vm: &mut VM, _args: &[Value]) -> Result<Value, RuntimeError> {
+    let items = vm
+        .args
+        .clone()
+        .iter()
+        .map(|arg| Value::Obj(vm.heap.intern(arg)))
+        .collect();
+
+    Ok(Value::Obj(vm.heap.alloc(Obj::List(List { items }))))
+}
+
+/// Returns the value of an environment variable, or nil if it isn't set or
+/// isn't valid Unicode.
+pub fn env(vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    let Some(Obj::String(name)) = vm.heap.object(args[0]) else {
+        return Err(RuntimeError::new(
+            RuntimeErrorKind::TypeError,
+            "Argument to 'env' must be a string.",
+        ));
+    };
+
+    Ok(match env::var(name.as_str()) {
+        Ok(value) => Value::Obj(vm.heap.intern(&value)),
+        Err(_) => Value::Nil,
+    })
+}
+
+/// Stops the script with the given status. The VM returns it as
+/// [`VMError::Exit`](crate::vm::VMError::Exit) rather than ending the
+/// process, so an embedding host decides what exiting means.
+pub fn exit(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+    let status = match args[0] {
+        Value::Number(status) if status.fract() == 0.0 && i32::try_from(status as i64).is_ok() => {
+            status as i32
+        }
+        _ => {
+            return Err(RuntimeError::new(
+                RuntimeErrorKind::TypeError,
+                "Argument to 'exit' must be a whole number.",
+            ))
+        }
+    };
+
+    Err(RuntimeError::new(
+        RuntimeErrorKind::Exit(status),
+        format!("exit({})", status),
+    ))
+}
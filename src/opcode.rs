@@ -1,11 +1,38 @@
 use serde::{Deserialize, Serialize};
 
-use crate::value::Value;
 use std::fmt;
 
-#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
+/// Where a closure finds one of its captured variables when it is created:
+/// either a local slot of the enclosing function or one of the enclosing
+/// function's own upvalues. Encoded as two operand bytes after
+/// `OpCode::Closure`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UpvalueIndex {
+    pub is_local: bool,
+    pub index: u8,
+}
+
+/// A single bytecode instruction. Operands follow the opcode byte in the
+/// chunk's code stream:
+///
+/// - `Constant`: 8-bit constant index.
+/// - Globals, properties, classes, methods and `GetSuper`: 8-bit constant
+///   index of the name.
+/// - Locals, upvalues and `Call`: 8-bit slot or argument count.
+/// - `Jump`, `JumpIfFalse` and `Loop`: 16-bit offset.
+/// - `Invoke` and `SuperInvoke`: 8-bit name constant, then 8-bit argument
+///   count.
+/// - `Closure`: 8-bit function constant, then an `is_local`/`index` byte pair
+///   for each upvalue.
+///
+/// Every opcode with a constant index has a `Long` twin, used once the
+/// constant table outgrows 8 bits, whose index is 24 bits instead. Other
+/// operands are the same. Multi-byte operands are big-endian.
+#[repr(u8)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum OpCode {
-    Constant(Value),
+    Constant,
+    ConstantLong,
     Not,
     Negate,
     Print,
@@ -21,20 +48,145 @@ pub enum OpCode {
     Multiply,
     Divide,
     Pop,
-    DefineGlobal(String),
-    GetGlobal(String),
-    SetGlobal(String),
+    DefineGlobal,
+    GetGlobal,
+    SetGlobal,
+    GetLocal,
+    SetLocal,
+    Jump,
+    JumpIfFalse,
+    Loop,
+    Call,
+    Closure,
+    GetUpvalue,
+    SetUpvalue,
+    CloseUpvalue,
+    Class,
+    GetProperty,
+    SetProperty,
+    Method,
+    Invoke,
+    Inherit,
+    GetSuper,
+    SuperInvoke,
+    GetIndex,
+    DefineGlobalLong,
+    GetGlobalLong,
+    SetGlobalLong,
+    ClosureLong,
+    ClassLong,
+    GetPropertyLong,
+    SetPropertyLong,
+    MethodLong,
+    InvokeLong,
+    GetSuperLong,
+    SuperInvokeLong,
+}
+
+impl OpCode {
+    /// The variant of this opcode that takes a 24-bit constant index, if it
+    /// takes a constant index at all.
+    pub fn long(self) -> Option<OpCode> {
+        Some(match self {
+            OpCode::Constant => OpCode::ConstantLong,
+            OpCode::DefineGlobal => OpCode::DefineGlobalLong,
+            OpCode::GetGlobal => OpCode::GetGlobalLong,
+            OpCode::SetGlobal => OpCode::SetGlobalLong,
+            OpCode::Closure => OpCode::ClosureLong,
+            OpCode::Class => OpCode::ClassLong,
+            OpCode::GetProperty => OpCode::GetPropertyLong,
+            OpCode::SetProperty => OpCode::SetPropertyLong,
+            OpCode::Method => OpCode::MethodLong,
+            OpCode::Invoke => OpCode::InvokeLong,
+            OpCode::GetSuper => OpCode::GetSuperLong,
+            OpCode::SuperInvoke => OpCode::SuperInvokeLong,
+            _ => return None,
+        })
+    }
+
+    /// Whether the constant index operand is 24 bits rather than 8.
+    pub fn is_long(self) -> bool {
+        matches!(
+            self,
+            OpCode::ConstantLong
+                | OpCode::DefineGlobalLong
+                | OpCode::GetGlobalLong
+                | OpCode::SetGlobalLong
+                | OpCode::ClosureLong
+                | OpCode::ClassLong
+                | OpCode::GetPropertyLong
+                | OpCode::SetPropertyLong
+                | OpCode::MethodLong
+                | OpCode::InvokeLong
+                | OpCode::GetSuperLong
+                | OpCode::SuperInvokeLong
+        )
+    }
 }
 
-impl From<f64> for OpCode {
-    fn from(value: f64) -> Self {
-        OpCode::Constant(Value::from(value))
+impl From<OpCode> for u8 {
+    fn from(value: OpCode) -> Self {
+        value as u8
     }
 }
 
-impl From<Value> for OpCode {
-    fn from(value: Value) -> Self {
-        Self::Constant(value)
+impl TryFrom<u8> for OpCode {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        Ok(match value {
+            0 => OpCode::Constant,
+            1 => OpCode::ConstantLong,
+            2 => OpCode::Not,
+            3 => OpCode::Negate,
+            4 => OpCode::Print,
+            5 => OpCode::Return,
+            6 => OpCode::Nil,
+            7 => OpCode::True,
+            8 => OpCode::False,
+            9 => OpCode::Equal,
+            10 => OpCode::Greater,
+            11 => OpCode::Less,
+            12 => OpCode::Add,
+            13 => OpCode::Subtract,
+            14 => OpCode::Multiply,
+            15 => OpCode::Divide,
+            16 => OpCode::Pop,
+            17 => OpCode::DefineGlobal,
+            18 => OpCode::GetGlobal,
+            19 => OpCode::SetGlobal,
+            20 => OpCode::GetLocal,
+            21 => OpCode::SetLocal,
+            22 => OpCode::Jump,
+            23 => OpCode::JumpIfFalse,
+            24 => OpCode::Loop,
+            25 => OpCode::Call,
+            26 => OpCode::Closure,
+            27 => OpCode::GetUpvalue,
+            28 => OpCode::SetUpvalue,
+            29 => OpCode::CloseUpvalue,
+            30 => OpCode::Class,
+            31 => OpCode::GetProperty,
+            32 => OpCode::SetProperty,
+            33 => OpCode::Method,
+            34 => OpCode::Invoke,
+            35 => OpCode::Inherit,
+            36 => OpCode::GetSuper,
+            37 => OpCode::SuperInvoke,
+            38 => OpCode::GetIndex,
+            39 => OpCode::DefineGlobalLong,
+            40 => OpCode::GetGlobalLong,
+            41 => OpCode::SetGlobalLong,
+            42 => OpCode::ClosureLong,
+            43 => OpCode::ClassLong,
+            44 => OpCode::GetPropertyLong,
+            45 => OpCode::SetPropertyLong,
+            46 => OpCode::MethodLong,
+            47 => OpCode::InvokeLong,
+            48 => OpCode::GetSuperLong,
+            49 => OpCode::SuperInvokeLong,
+            _ => return Err(value),
+        })
     }
 }
 
@@ -42,7 +194,8 @@ impl fmt::Display for OpCode {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             OpCode::Return => f.write_str("OP_RETURN"),
-            OpCode::Constant(value) => f.write_fmt(format_args!("OP_CONSTANT: {}", value)),
+            OpCode::Constant => f.write_str("OP_CONSTANT"),
+            OpCode::ConstantLong => f.write_str("OP_CONSTANT_LONG"),
             OpCode::Negate => f.write_str("OP_NEGATE"),
             OpCode::Add => f.write_str("OP_ADD"),
             OpCode::Subtract => f.write_str("OP_SUBTRACT"),
@@ -57,9 +210,39 @@ impl fmt::Display for OpCode {
             OpCode::Less => f.write_str("OP_LESS"),
             OpCode::Print => f.write_str("OP_PRINT"),
             OpCode::Pop => f.write_str("OP_POP"),
-            OpCode::DefineGlobal(name) => f.write_fmt(format_args!("OP_DEFINE_GLOBAL: {}", name)),
-            OpCode::GetGlobal(name) => f.write_fmt(format_args!("OP_GET_GLOBAL: {}", name)),
-            OpCode::SetGlobal(name) => f.write_fmt(format_args!("OP_SET_GLOBAL: {}", name)),
+            OpCode::DefineGlobal => f.write_str("OP_DEFINE_GLOBAL"),
+            OpCode::GetGlobal => f.write_str("OP_GET_GLOBAL"),
+            OpCode::SetGlobal => f.write_str("OP_SET_GLOBAL"),
+            OpCode::GetLocal => f.write_str("OP_GET_LOCAL"),
+            OpCode::SetLocal => f.write_str("OP_SET_LOCAL"),
+            OpCode::Jump => f.write_str("OP_JUMP"),
+            OpCode::JumpIfFalse => f.write_str("OP_JUMP_IF_FALSE"),
+            OpCode::Loop => f.write_str("OP_LOOP"),
+            OpCode::Call => f.write_str("OP_CALL"),
+            OpCode::Closure => f.write_str("OP_CLOSURE"),
+            OpCode::GetUpvalue => f.write_str("OP_GET_UPVALUE"),
+            OpCode::SetUpvalue => f.write_str("OP_SET_UPVALUE"),
+            OpCode::CloseUpvalue => f.write_str("OP_CLOSE_UPVALUE"),
+            OpCode::Class => f.write_str("OP_CLASS"),
+            OpCode::GetProperty => f.write_str("OP_GET_PROPERTY"),
+            OpCode::SetProperty => f.write_str("OP_SET_PROPERTY"),
+            OpCode::Method => f.write_str("OP_METHOD"),
+            OpCode::Invoke => f.write_str("OP_INVOKE"),
+            OpCode::Inherit => f.write_str("OP_INHERIT"),
+            OpCode::GetSuper => f.write_str("OP_GET_SUPER"),
+            OpCode::SuperInvoke => f.write_str("OP_SUPER_INVOKE"),
+            OpCode::GetIndex => f.write_str("OP_GET_INDEX"),
+            OpCode::DefineGlobalLong => f.write_str("OP_DEFINE_GLOBAL_LONG"),
+            OpCode::GetGlobalLong => f.write_str("OP_GET_GLOBAL_LONG"),
+            OpCode::SetGlobalLong => f.write_str("OP_SET_GLOBAL_LONG"),
+            OpCode::ClosureLong => f.write_str("OP_CLOSURE_LONG"),
+            OpCode::ClassLong => f.write_str("OP_CLASS_LONG"),
+            OpCode::GetPropertyLong => f.write_str("OP_GET_PROPERTY_LONG"),
+            OpCode::SetPropertyLong => f.write_str("OP_SET_PROPERTY_LONG"),
+            OpCode::MethodLong => f.write_str("OP_METHOD_LONG"),
+            OpCode::InvokeLong => f.write_str("OP_INVOKE_LONG"),
+            OpCode::GetSuperLong => f.write_str("OP_GET_SUPER_LONG"),
+            OpCode::SuperInvokeLong => f.write_str("OP_SUPER_INVOKE_LONG"),
         }
     }
 }
@@ -0,0 +1,233 @@
+//! The interactive prompt: reads entries a line at a time, runs them against
+//! one long-lived VM and handles `:` meta-commands.
+
+use std::{env, fs, mem, path::PathBuf};
+
+use rustyline::{error::ReadlineError, DefaultEditor};
+
+use crate::{
+    disassembler::disassemble_chunk,
+    scanner::{Scanner, TokenType, TokenValue},
+    value::Value,
+    vm::{VMError, VM},
+};
+
+const PROMPT: &str = "> ";
+const CONTINUATION_PROMPT: &str = "... ";
+const HISTORY_FILE: &str = ".bytelox_history";
+const REPL_NAME: &str = "<repl>";
+
+pub struct Repl {
+    vm: VM,
+    /// The VM as it was first configured, restored by `:reset` so flags like
+    /// `--trace` survive it.
+    initial: VM,
+}
+
+impl Repl {
+    pub fn new(vm: VM) -> Self {
+        Self {
+            initial: vm.clone(),
+            vm,
+        }
+    }
+
+    /// Reads and runs entries until end of input or a call to `exit()`, and
+    /// returns the exit status. Ctrl-C discards the entry being typed; Ctrl-D
+    /// exits with status 0.
+    pub fn run(&mut self) -> rustyline::Result<i32> {
+        let mut editor = DefaultEditor::new()?;
+        let mut history = history_path();
+        if let Some(path) = &history {
+            // There's no history yet on the first run.
+            let _ = editor.load_history(path);
+        }
+
+        let mut entry = String::new();
+        loop {
+            let prompt = if entry.is_empty() {
+                PROMPT
+            } else {
+                CONTINUATION_PROMPT
+            };
+
+            match editor.readline(prompt) {
+                Ok(line) => {
+                    if !entry.is_empty() {
+                        entry.push('\n');
+                    }
+                    entry.push_str(&line);
+                }
+                Err(ReadlineError::Interrupted) => {
+                    entry.clear();
+                    continue;
+                }
+                Err(ReadlineError::Eof) => return Ok(0),
+                Err(error) => return Err(error),
+            }
+
+            if is_incomplete(&entry) {
+                continue;
+            }
+
+            let entry = mem::take(&mut entry);
+            if entry.trim().is_empty() {
+                continue;
+            }
+
+            let exit = self.eval(&entry);
+
+            // History is a convenience, so failing to save it (say, with no
+            // writable home directory) only warns once and stops saving.
+            let _ = editor.add_history_entry(entry.as_str());
+            if let Some(path) = &history {
+                if let Err(error) = editor.save_history(path) {
+                    eprintln!("warning: couldn't save history: {}", error);
+                    history = None;
+                }
+            }
+
+            if let Some(status) = exit {
+                return Ok(status);
+            }
+        }
+    }
+
+    /// Runs one entry, returning the status if it called `exit()`.
+    fn eval(&mut self, entry: &str) -> Option<i32> {
+        if let Some(command) = entry.trim().strip_prefix(':') {
+            return self.command(command);
+        }
+
+        match self.vm.eval(entry) {
+            Ok(Value::Nil) => {}
+            Ok(value) => println!("{}", value.repr(&self.vm.heap)),
+            Err(VMError::Exit(status)) => return Some(status),
+            Err(error) => error.report(REPL_NAME, entry),
+        }
+        None
+    }
+
+    fn command(&mut self, command: &str) -> Option<i32> {
+        let (name, argument) = command
+            .split_once(char::is_whitespace)
+            .map_or((command, ""), |(name, argument)| (name, argument.trim()));
+
+        match (name, argument) {
+            ("globals", "") => self.print_globals(),
+            ("dis", expression) if !expression.is_empty() => self.disassemble(expression),
+            ("load", path) if !path.is_empty() => return self.load(path),
+            ("reset", "") => self.vm = self.initial.clone(),
+            _ => eprintln!(
+                "Unknown command ':{}'. Commands are :globals, :dis <expr>, :load <path> and :reset.",
+                command
+            ),
+        }
+        None
+    }
+
+    fn print_globals(&self) {
+        let heap = &self.vm.heap;
+        let mut globals: Vec<_> = self
+            .vm
+            .globals
+            .iter()
+            .map(|(&name, &value)| (heap.string(name).as_str(), value.repr(heap)))
+            .collect();
+        globals.sort_by_key(|&(name, _)| name);
+
+        for (name, value) in globals {
+            println!("{} = {}", name, value);
+        }
+    }
+
+    /// Prints the bytecode `expression` compiles to, without running it.
+    fn disassemble(&mut self, expression: &str) {
+        let source = format!("{};", expression.trim_end_matches(';'));
+
+        match self.vm.compiler.compile(&source, &mut self.vm.heap) {
+            Ok(function) => {
+                let heap = &self.vm.heap;
+                print!(
+                    "{}",
+                    disassemble_chunk(&heap.function(function).chunk, REPL_NAME, heap)
+                );
+            }
+            Err(diagnostics) => VMError::CompileTime(diagnostics).report(REPL_NAME, &source),
+        }
+    }
+
+    fn load(&mut self, path: &str) -> Option<i32> {
+        let source = match fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(error) => {
+                eprintln!("error: {}: {}", path, error);
+                return None;
+            }
+        };
+
+        match self.vm.interpret(&source) {
+            Ok(()) => None,
+            Err(VMError::Exit(status)) => Some(status),
+            Err(error) => {
+                error.report(path, &source);
+                None
+            }
+        }
+    }
+}
+
+fn history_path() -> Option<PathBuf> {
+    env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE))
+}
+
+/// Returns true if `source` ends inside brackets of any kind or a string, so
+/// the REPL should keep reading lines before running it.
+pub fn is_incomplete(source: &str) -> bool {
+    let mut scanner = Scanner::new();
+    scanner.input(source);
+
+    let mut depth = 0;
+    loop {
+        let token = scanner.scan_token();
+        match token.r#type {
+            TokenType::LeftParen | TokenType::LeftBrace | TokenType::LeftBracket => depth += 1,
+            TokenType::RightParen | TokenType::RightBrace | TokenType::RightBracket => depth -= 1,
+            TokenType::Error => {
+                if let Some(TokenValue::Error(message)) = &token.value {
+                    if message.starts_with("Unterminated string") {
+                        return true;
+                    }
+                }
+            }
+            TokenType::Eof => return depth > 0,
+            _ => {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn balanced_entries_are_complete() {
+        assert!(!is_incomplete("print 1;"));
+        assert!(!is_incomplete("fun f() { return (1 + 2); }"));
+        assert!(!is_incomplete("print \"{\";"));
+    }
+
+    #[test]
+    fn open_brackets_and_strings_continue() {
+        assert!(is_incomplete("fun f() {"));
+        assert!(is_incomplete("fun f() {\n  if (true) {\n    print 1;\n  }"));
+        assert!(is_incomplete("print (1 +"));
+        assert!(is_incomplete("var l = [1,"));
+        assert!(is_incomplete("print \"multi\nline"));
+    }
+
+    #[test]
+    fn extra_closers_are_left_to_the_compiler() {
+        assert!(!is_incomplete("}"));
+    }
+}
@@ -6,6 +6,11 @@ pub struct Scanner {
     source: Vec<char>,
     current: usize,
     line: usize,
+    /// The offset of the first character of the current line.
+    line_start: usize,
+    /// The column of the token being scanned, so tokens know it without
+    /// searching back for the start of their line.
+    token_column: usize,
     keywords: HashMap<char, Vec<String>>,
 }
 
@@ -14,6 +19,8 @@ impl Default for Scanner {
         Self {
             source: vec![],
             line: 1,
+            line_start: 0,
+            token_column: 0,
             current: 0,
             keywords: HashMap::from([
                 ('a', vec!["and".to_string()]),
@@ -46,8 +53,17 @@ impl Scanner {
         self.source = source.chars().collect()
     }
 
+    /// Converts a character offset into the source to a byte offset.
+    pub fn byte_offset(&self, offset: usize) -> usize {
+        self.source[..offset.min(self.source.len())]
+            .iter()
+            .map(|c| c.len_utf8())
+            .sum()
+    }
+
     pub fn scan_token(&mut self) -> Token {
         self.skip_whitespace();
+        self.token_column = self.current - self.line_start + 1;
         if self.is_at_end() {
             return Token {
                 value: None,
@@ -55,38 +71,41 @@ impl Scanner {
                 length: 1,
                 start: self.current,
                 line: self.line,
+                column: self.token_column,
             };
         }
         let c = self.advance();
         match c {
-            '(' | ')' | '{' | '}' | ';' | '.' | ',' | '-' | '+' | '*' => Token {
+            '(' | ')' | '{' | '}' | '[' | ']' | ';' | '.' | ',' | '-' | '+' | '*' => Token {
                 value: None,
                 r#type: TokenType::from(c),
                 length: 1,
-                start: self.current,
+                start: self.current - 1,
                 line: self.line,
+                column: self.token_column,
             },
             '/' => {
-                if self.peek_next() == '/' {
-                    let mut length = 1;
+                if self.peek() == '/' {
+                    let start = self.current - 1;
                     while self.peek() != '\n' && !self.is_at_end() {
                         self.advance();
-                        length += 1;
                     }
                     Token {
                         value: None,
                         r#type: TokenType::Comment,
-                        start: self.current,
+                        start,
                         line: self.line,
-                        length,
+                        column: self.token_column,
+                        length: self.current - start,
                     }
                 } else {
                     Token {
                         value: None,
                         r#type: TokenType::Slash,
                         length: 1,
-                        start: self.current,
+                        start: self.current - 1,
                         line: self.line,
+                        column: self.token_column,
                     }
                 }
             }
@@ -97,9 +116,10 @@ impl Scanner {
             _ => Token {
                 value: Some(TokenValue::Error(format!("Unknown Token {}", c))),
                 r#type: TokenType::Error,
-                start: self.current,
+                start: self.current - 1,
                 length: 1,
                 line: self.line,
+                column: self.token_column,
             },
         }
     }
@@ -112,50 +132,47 @@ impl Scanner {
                 value: None,
                 r#type: TokenType::from(rel_eq.as_str()),
                 length: 2,
-                start: self.current,
+                start: self.current - 2,
                 line: self.line,
+                column: self.token_column,
             }
         } else {
             Token {
                 value: None,
                 r#type: TokenType::from(c),
                 length: 1,
-                start: self.current,
+                start: self.current - 1,
                 line: self.line,
+                column: self.token_column,
             }
         }
     }
 
     fn identifier(&mut self, c: char) -> Token {
-        let potential_matches = self.keywords.get(&c).unwrap_or(&vec![]).clone();
-
         let start = self.current.saturating_sub(1);
-        for keyword in potential_matches {
-            if self.check_keyword(&keyword) {
-                self.current += keyword.len();
-                return Token {
-                    value: None,
-                    r#type: TokenType::from(keyword.as_str()),
-                    length: keyword.len(),
-                    start,
-                    line: self.line,
-                };
-            }
+
+        while self.peek().is_ascii_alphanumeric() || self.peek() == '_' {
+            self.advance();
         }
 
-        let mut identifier = String::new();
-        identifier.push(self.prev());
+        let identifier: String = self.source[start..self.current].iter().collect();
+        let length = self.current - start;
 
-        loop {
-            let c = self.advance();
-            if c.is_ascii_alphanumeric() {
-                identifier.push(c);
-            } else {
-                break;
-            }
-        }
+        let is_keyword = self
+            .keywords
+            .get(&c)
+            .is_some_and(|keywords| keywords.contains(&identifier));
 
-        let length = identifier.len();
+        if is_keyword {
+            return Token {
+                value: None,
+                r#type: TokenType::from(identifier.as_str()),
+                length,
+                start,
+                line: self.line,
+                column: self.token_column,
+            };
+        }
 
         Token {
             value: Some(TokenValue::Identifier(identifier)),
@@ -163,26 +180,10 @@ impl Scanner {
             length,
             start,
             line: self.line,
+            column: self.token_column,
         }
     }
 
-    fn check_keyword(&self, keyword: &str) -> bool {
-        if self.current + keyword.len() >= self.source.len() {
-            return false;
-        }
-
-        let mut temp_index = self.current.saturating_sub(1);
-
-        for c in keyword.chars() {
-            if self.source[temp_index] != c {
-                return false;
-            }
-            temp_index += 1;
-        }
-
-        true
-    }
-
     fn number(&mut self) -> Token {
         let mut value = String::new();
         let start = self.current - 1;
@@ -211,6 +212,7 @@ impl Scanner {
             length,
             start,
             line: self.line,
+            column: self.token_column,
         }
     }
 
@@ -221,25 +223,27 @@ impl Scanner {
         while self.peek() != '"' && !self.is_at_end() {
             if self.peek() == '\n' {
                 self.line += 1;
+                self.line_start = self.current + 1;
             }
             value.push(self.advance());
         }
 
         if self.is_at_end() {
             let error = "Unterminated string".to_string();
-            let length = error.len();
+            let length = self.current - start;
             return Token {
                 value: Some(TokenValue::Error(error)),
                 r#type: TokenType::Error,
                 start,
                 length,
                 line: self.line,
+                column: self.token_column,
             };
         }
 
         self.advance();
 
-        let length = value.len() + 2;
+        let length = self.current - start;
 
         Token {
             value: Some(TokenValue::String(value)),
@@ -247,6 +251,7 @@ impl Scanner {
             start,
             length,
             line: self.line,
+            column: self.token_column,
         }
     }
 
@@ -270,6 +275,7 @@ impl Scanner {
                 '\n' => {
                     self.advance();
                     self.line += 1;
+                    self.line_start = self.current;
                 }
                 _ => break,
             }
@@ -281,7 +287,7 @@ impl Scanner {
     }
 
     fn peek_next(&self) -> char {
-        if self.is_at_end() {
+        if self.current + 1 >= self.source.len() {
             '\0'
         } else {
             self.source[self.current + 1]
@@ -318,6 +324,8 @@ pub enum TokenType {
     RightParen,
     LeftBrace,
     RightBrace,
+    LeftBracket,
+    RightBracket,
     Comma,
     Dot,
     Minus,
@@ -368,6 +376,8 @@ impl From<char> for TokenType {
             ')' => TokenType::RightParen,
             '{' => TokenType::LeftBrace,
             '}' => TokenType::RightBrace,
+            '[' => TokenType::LeftBracket,
+            ']' => TokenType::RightBracket,
             ',' => TokenType::Comma,
             '.' => TokenType::Dot,
             '-' => TokenType::Minus,
@@ -391,6 +401,8 @@ impl From<&str> for TokenType {
             ")" => TokenType::RightParen,
             "{" => TokenType::LeftBrace,
             "}" => TokenType::RightBrace,
+            "[" => TokenType::LeftBracket,
+            "]" => TokenType::RightBracket,
             "," => TokenType::Comma,
             "." => TokenType::Dot,
             "-" => TokenType::Minus,
@@ -442,6 +454,8 @@ pub struct Token {
     pub start: usize,
     pub length: usize,
     pub line: usize,
+    /// The 1-based column of `start` on the line it begins.
+    pub column: usize,
 }
 
 #[cfg(test)]
@@ -487,4 +501,6 @@ mod tests {
     test_scanner!(keywords, "for while print return or nil");
     test_scanner!(multiline, "10\n20\n30");
     test_scanner!(string_concat, "\"hello\" + \"world\"");
+    test_scanner!(comment, "// note\n10 / 2 // half");
+    test_scanner!(columns, "\"a\nbc\" x\n  y");
 }
@@ -1,32 +1,151 @@
 use std::fmt;
 
-use serde::{Deserialize, Serialize};
+use crate::{
+    chunk::Chunk,
+    heap::{Handle, Heap},
+    interner::{LoxString, StringMap},
+    vm::{RuntimeError, VM},
+};
 
-#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
-pub enum Obj {
-    String(String),
+#[derive(Default, Debug, Clone, PartialEq)]
+pub struct Function {
+    pub arity: usize,
+    pub upvalue_count: usize,
+    pub name: String,
+    pub chunk: Chunk,
+    /// Which of its compiler's sources the function was compiled from,
+    /// numbered from 1, or 0 if it was loaded from bytecode. Runtime errors
+    /// are only rendered against a source text the failure came from.
+    pub source: usize,
 }
 
-impl fmt::Display for Obj {
+impl fmt::Display for Function {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Obj::String(str) => f.write_fmt(format_args!("{}", str)),
+        if self.name.is_empty() {
+            f.write_str("<script>")
+        } else {
+            f.write_fmt(format_args!("<fn {}>", self.name))
         }
     }
 }
 
-#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
+/// A variable captured by a closure. It points at a stack slot while the
+/// variable is still live there, and holds the value itself once the slot
+/// has been popped.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Upvalue {
+    Open(usize),
+    Closed(Value),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Closure {
+    pub function: Handle,
+    pub upvalues: Vec<Handle>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Class {
+    pub name: Handle,
+    pub methods: StringMap<Handle>,
+}
+
+impl Class {
+    pub fn new(name: Handle) -> Self {
+        Self {
+            name,
+            methods: StringMap::default(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Instance {
+    pub class: Handle,
+    pub fields: StringMap<Value>,
+}
+
+impl Instance {
+    pub fn new(class: Handle) -> Self {
+        Self {
+            class,
+            fields: StringMap::default(),
+        }
+    }
+}
+
+/// A method closure paired with the instance it was accessed on.
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoundMethod {
+    pub receiver: Value,
+    pub method: Handle,
+}
+
+/// A fixed sequence of values, such as the script arguments returned by
+/// `args()`. Scripts can index it but not build or change one.
+#[derive(Debug, Clone, PartialEq)]
+pub struct List {
+    pub items: Vec<Value>,
+}
+
+pub type NativeFn = fn(&mut VM, &[Value]) -> Result<Value, RuntimeError>;
+
+/// A function implemented by the host and exposed to scripts as a global.
+#[derive(Debug, Clone)]
+pub struct Native {
+    pub name: String,
+    pub arity: usize,
+    pub function: NativeFn,
+}
+
+/// An object owned by a [`Heap`]. Objects refer to each other by handle.
+#[derive(Debug, Clone)]
+pub enum Obj {
+    String(LoxString),
+    Function(Function),
+    Closure(Closure),
+    Upvalue(Upvalue),
+    Class(Class),
+    Instance(Instance),
+    BoundMethod(BoundMethod),
+    Native(Native),
+    List(List),
+}
+
+/// Objects compare by handle, which is identity. Strings are interned, so
+/// for them that is the same as comparing contents.
+#[derive(Debug, Clone, Copy, PartialEq)]
 pub enum Value {
     Number(f64),
     Bool(bool),
     Nil,
-    Obj(Obj),
+    Obj(Handle),
 }
 
 impl Value {
     pub fn is_falsey(&self) -> bool {
         matches!(self, Value::Bool(false) | Value::Nil)
     }
+
+    /// Formats the value, looking up objects in `heap`.
+    pub fn display(self, heap: &Heap) -> ValueDisplay<'_> {
+        ValueDisplay {
+            value: self,
+            heap,
+            repr: false,
+        }
+    }
+
+    /// Formats the value the way the REPL echoes it: like
+    /// [`Value::display`], but with strings quoted and escaped and classes
+    /// and natives labelled, so the type is clear.
+    pub fn repr(self, heap: &Heap) -> ValueDisplay<'_> {
+        ValueDisplay {
+            value: self,
+            heap,
+            repr: true,
+        }
+    }
 }
 
 impl From<f64> for Value {
@@ -35,13 +154,56 @@ impl From<f64> for Value {
     }
 }
 
-impl fmt::Display for Value {
+pub struct ValueDisplay<'a> {
+    value: Value,
+    heap: &'a Heap,
+    repr: bool,
+}
+
+impl fmt::Display for ValueDisplay<'_> {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Value::Number(num) => f.write_fmt(format_args!("{}", num)),
-            Value::Bool(bool) => f.write_fmt(format_args!("{}", bool)),
-            Value::Nil => f.write_str("nil"),
-            Value::Obj(obj_type) => f.write_fmt(format_args!("{}", obj_type)),
+        let heap = self.heap;
+        let handle = match self.value {
+            Value::Number(num) => return f.write_fmt(format_args!("{}", num)),
+            Value::Bool(bool) => return f.write_fmt(format_args!("{}", bool)),
+            Value::Nil => return f.write_str("nil"),
+            Value::Obj(handle) => handle,
+        };
+
+        match heap.get(handle) {
+            Obj::String(str) if self.repr => f.write_fmt(format_args!("{:?}", str.as_str())),
+            Obj::String(str) => f.write_fmt(format_args!("{}", str)),
+            Obj::Function(function) => f.write_fmt(format_args!("{}", function)),
+            Obj::Closure(closure) => {
+                f.write_fmt(format_args!("{}", heap.function(closure.function)))
+            }
+            Obj::Upvalue(_) => f.write_str("upvalue"),
+            Obj::Class(class) if self.repr => {
+                f.write_fmt(format_args!("<class {}>", heap.string(class.name)))
+            }
+            Obj::Class(class) => f.write_fmt(format_args!("{}", heap.string(class.name))),
+            Obj::Instance(instance) => f.write_fmt(format_args!(
+                "{} instance",
+                heap.string(heap.class(instance.class).name)
+            )),
+            Obj::BoundMethod(bound) => f.write_fmt(format_args!(
+                "{}",
+                heap.function(heap.closure(bound.method).function)
+            )),
+            Obj::Native(native) if self.repr => {
+                f.write_fmt(format_args!("<native fn {}>", native.name))
+            }
+            Obj::Native(_) => f.write_str("<native fn>"),
+            Obj::List(list) => {
+                f.write_str("[")?;
+                for (i, item) in list.items.iter().enumerate() {
+                    if i > 0 {
+                        f.write_str(", ")?;
+                    }
+                    write!(f, "{}", item.repr(heap))?;
+                }
+                f.write_str("]")
+            }
         }
     }
 }
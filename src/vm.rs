@@ -1,74 +1,457 @@
-use std::{collections::HashMap, fmt};
+use std::{
+    fmt::{self, Write},
+    io::{self, IsTerminal},
+    iter,
+};
 
 use crate::{
+    chunk::Location,
     compiler::Compiler,
+    diagnostic::Diagnostic,
+    disassembler::disassemble_instruction,
+    heap::{Handle, Heap},
+    interner::StringMap,
+    native,
     opcode::OpCode,
-    value::{Obj, Value},
+    value::{BoundMethod, Class, Closure, Instance, Native, NativeFn, Obj, Upvalue, Value},
 };
 
+/// How deep calls can nest before the VM reports a stack overflow. Frames and
+/// the value stack live on the heap, so this only guards against runaway
+/// recursion rather than the host's own stack.
+const FRAMES_MAX: usize = 1024;
+
 #[derive(Debug, Clone)]
 pub enum VMError {
-    CompileTime,
-    Runtime,
+    CompileTime(Vec<Diagnostic>),
+    Runtime(RuntimeError),
+    /// The script called `exit(status)`. It's up to the host whether that
+    /// ends the process.
+    Exit(i32),
+}
+
+impl VMError {
+    /// Renders the error against `source` as rustc-style snippets. See
+    /// [`Diagnostic::render`].
+    pub fn render(&self, name: &str, source: &str, color: bool) -> String {
+        match self {
+            VMError::CompileTime(diagnostics) => diagnostics
+                .iter()
+                .map(|diagnostic| diagnostic.render(name, source, color))
+                .collect::<Vec<_>>()
+                .join("\n"),
+            // Without a location the failure was in code compiled from some
+            // other source, such as an earlier REPL entry.
+            VMError::Runtime(error) if error.location.is_some() => {
+                error.diagnostic().render(name, source, color)
+            }
+            VMError::Runtime(error) => format!("error: {}\n", error),
+            VMError::Exit(_) => String::new(),
+        }
+    }
+
+    /// Prints the error to stderr, rendered against `source` and colored if
+    /// stderr is a terminal.
+    pub fn report(&self, name: &str, source: &str) {
+        eprint!("{}", self.render(name, source, io::stderr().is_terminal()));
+    }
 }
 
 impl fmt::Display for VMError {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
-            VMError::CompileTime => write!(f, "compile time error"),
-            VMError::Runtime => write!(f, "runtime error"),
+            VMError::CompileTime(diagnostics) => {
+                for (i, diagnostic) in diagnostics.iter().enumerate() {
+                    if i > 0 {
+                        writeln!(f)?;
+                    }
+                    write!(f, "{}", diagnostic)?;
+                }
+                Ok(())
+            }
+            VMError::Runtime(error) => write!(f, "{}", error),
+            VMError::Exit(status) => write!(f, "exited with status {}", status),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RuntimeErrorKind {
+    /// An operand or receiver had the wrong type for the operation.
+    TypeError,
+    UndefinedVariable,
+    UndefinedProperty,
+    ArityMismatch,
+    StackOverflow,
+    /// A list was indexed past its end.
+    IndexOutOfRange,
+    /// The chunk contained a byte that isn't a valid opcode.
+    InvalidOpcode,
+    /// A native function failed for a reason of its own.
+    Native,
+    /// Not a failure: a native asking to end the script, which the VM
+    /// reports as [`VMError::Exit`].
+    Exit(i32),
+}
+
+/// One function activation that was live when a runtime error occurred.
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceFrame {
+    /// The function's name, empty for the top-level script.
+    pub function: String,
+    pub location: Location,
+}
+
+impl fmt::Display for TraceFrame {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.function.is_empty() {
+            write!(f, "[line {}] in script", self.location.line)
+        } else {
+            write!(f, "[line {}] in {}()", self.location.line, self.function)
         }
     }
 }
 
-#[derive(Default, Debug, Clone)]
+/// An error that aborted execution. Natives only need to fill in `kind` and
+/// `message`; the VM attaches `location` and `trace` as the error leaves it.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RuntimeError {
+    pub kind: RuntimeErrorKind,
+    pub message: String,
+    /// Where the failing instruction is in the source being run, or `None`
+    /// if it was compiled from a different one.
+    pub location: Option<Location>,
+    /// The call stack at the point of failure, innermost frame first.
+    pub trace: Vec<TraceFrame>,
+}
+
+impl RuntimeError {
+    pub fn new(kind: RuntimeErrorKind, message: impl Into<String>) -> Self {
+        Self {
+            kind,
+            message: message.into(),
+            location: None,
+            trace: vec![],
+        }
+    }
+
+    /// Describes the error as a diagnostic pointing at the failing
+    /// instruction, with the call stack as notes.
+    pub fn diagnostic(&self) -> Diagnostic {
+        let mut diagnostic =
+            Diagnostic::error(&self.message, 0..0, self.location.unwrap_or_default());
+        diagnostic.notes = self.trace_lines();
+        diagnostic
+    }
+
+    /// The trace, one line per frame, except that a frame repeated more
+    /// than twice in a row, as in runaway recursion, is listed once with a
+    /// count of the rest.
+    fn trace_lines(&self) -> Vec<String> {
+        let mut lines = vec![];
+        let mut frames = self.trace.iter().peekable();
+        while let Some(frame) = frames.next() {
+            let mut repeats = 0;
+            while frames.next_if_eq(&frame).is_some() {
+                repeats += 1;
+            }
+
+            lines.push(frame.to_string());
+            match repeats {
+                0 => {}
+                1 => lines.push(frame.to_string()),
+                _ => lines.push(format!("... {} more", repeats)),
+            }
+        }
+        lines
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&self.message)?;
+        for line in self.trace_lines() {
+            write!(f, "\n{}", line)?;
+        }
+        Ok(())
+    }
+}
+
+/// An active function invocation. `slots` is the index of the frame's first
+/// stack slot, which holds the callee itself. `function` is the closure's
+/// function, kept alongside it to save a lookup on every byte read.
+#[derive(Debug, Clone)]
+pub struct CallFrame {
+    pub closure: Handle,
+    pub function: Handle,
+    pub ip: usize,
+    pub slots: usize,
+}
+
+/// Called with the VM just before each instruction executes, while
+/// `VM::debug` is set.
+pub type Tracer = fn(&VM);
+
+/// The default tracer: prints the stack and the instruction about to execute
+/// to stderr.
+pub fn print_trace(vm: &VM) {
+    let mut out = String::from("          ");
+    for value in &vm.stack {
+        let _ = write!(out, "[ {} ]", value.display(&vm.heap));
+    }
+    out.push('\n');
+
+    if let Some(frame) = vm.frames.last() {
+        let chunk = &vm.heap.function(frame.function).chunk;
+        disassemble_instruction(chunk, frame.ip, &vm.heap, &mut out);
+    }
+    eprint!("{}", out);
+}
+
+#[derive(Debug, Clone)]
 pub struct VM {
-    pub chunks: Vec<OpCode>,
-    pub index: usize,
+    pub frames: Vec<CallFrame>,
     pub debug: bool,
+    pub tracer: Option<Tracer>,
     pub stack: Vec<Value>,
     pub compiler: Compiler,
-    pub globals: HashMap<String, Value>,
+    pub heap: Heap,
+    pub globals: StringMap<Value>,
+    /// Upvalues still pointing at live stack slots, so closures capturing the
+    /// same variable share a single upvalue.
+    pub open_upvalues: Vec<Handle>,
+    /// The interned name of initializer methods, looked up on every class
+    /// call.
+    init_string: Handle,
+    /// The command-line arguments after the script name.
+    pub args: Vec<String>,
+}
+
+impl Default for VM {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl VM {
     pub fn new() -> Self {
-        Self::default()
+        let mut heap = Heap::new();
+        let init_string = heap.intern("init");
+
+        let mut vm = Self {
+            frames: vec![],
+            debug: false,
+            tracer: None,
+            stack: vec![],
+            compiler: Compiler::new(),
+            heap,
+            globals: StringMap::default(),
+            open_upvalues: vec![],
+            init_string,
+            args: vec![],
+        };
+        vm.define_native("clock", 0, native::clock);
+        vm.define_native("len", 1, native::len);
+        vm.define_native("args", 0, native::args);
+        vm.define_native("env", 1, native::env);
+        vm.define_native("exit", 1, native::exit);
+        vm
     }
 
+    /// Enables execution tracing, calling `tracer` before every instruction.
+    pub fn set_tracer(&mut self, tracer: Tracer) {
+        self.tracer = Some(tracer);
+        self.debug = true;
+    }
+
+    /// Exposes a Rust function to scripts as the global `name`.
+    pub fn define_native(&mut self, name: &str, arity: usize, function: NativeFn) {
+        let native = self.heap.alloc(Obj::Native(Native {
+            name: name.to_string(),
+            arity,
+            function,
+        }));
+        let name = self.heap.intern(name);
+        self.globals.insert(name, Value::Obj(native));
+    }
+
+    /// Compiles `source` into a fresh script and runs it. Globals defined by
+    /// earlier calls stay visible, and a call that fails leaves the VM ready
+    /// for the next one.
     pub fn interpret(&mut self, source: &str) -> Result<(), VMError> {
-        let chunks = self.compiler.compile(source);
+        let function = self
+            .compiler
+            .compile(source, &mut self.heap)
+            .map_err(VMError::CompileTime)?;
+        self.interpret_function(function).map(|_| ())
+    }
 
-        if let Ok(parsed_chunks) = chunks {
-            self.chunks = parsed_chunks;
+    /// Runs `source` as a REPL entry and returns the value of its final
+    /// expression statement, or nil if it doesn't end with one. The value is
+    /// only kept alive until the VM runs again.
+    pub fn eval(&mut self, source: &str) -> Result<Value, VMError> {
+        let function = self
+            .compiler
+            .compile_repl(source, &mut self.heap)
+            .map_err(VMError::CompileTime)?;
+        self.interpret_function(function)
+    }
+
+    /// Runs an already compiled top-level script, such as one loaded from a
+    /// `.loxc` file. `function` must have been allocated in this VM's heap.
+    /// Returns whatever the script returns, which is nil unless it was
+    /// compiled with [`Compiler::compile_repl`].
+    pub fn interpret_function(&mut self, function: Handle) -> Result<Value, VMError> {
+        let closure = self.heap.alloc(Obj::Closure(Closure {
+            function,
+            upvalues: vec![],
+        }));
+
+        self.stack.push(Value::Obj(closure));
+
+        let result = self.call(closure, 0).and_then(|_| self.run());
+        if result.is_err() {
+            self.reset_stack();
         }
+        result
+    }
 
-        self.run()
+    /// Unwinds everything after an error so the next `interpret` or `eval`
+    /// starts clean. Globals survive.
+    fn reset_stack(&mut self) {
+        // Closures that escaped the aborted frames may still capture their
+        // slots; close those upvalues so the closures outlive the stack.
+        self.close_upvalues(0);
+        self.stack.clear();
+        self.frames.clear();
     }
 
-    fn run(&mut self) -> Result<(), VMError> {
-        dbg!(&self);
-        while self.index < self.chunks.len() {
+    fn frame(&self) -> &CallFrame {
+        self.frames.last().unwrap()
+    }
+
+    fn frame_mut(&mut self) -> &mut CallFrame {
+        self.frames.last_mut().unwrap()
+    }
+
+    fn read_byte(&mut self) -> u8 {
+        let frame = self.frames.last_mut().unwrap();
+        let byte = self.heap.function(frame.function).chunk.code[frame.ip];
+        frame.ip += 1;
+        byte
+    }
+
+    fn read_u16(&mut self) -> u16 {
+        let frame = self.frames.last_mut().unwrap();
+        let value = self.heap.function(frame.function).chunk.read_u16(frame.ip);
+        frame.ip += 2;
+        value
+    }
+
+    fn read_u24(&mut self) -> usize {
+        let frame = self.frames.last_mut().unwrap();
+        let value = self.heap.function(frame.function).chunk.read_u24(frame.ip);
+        frame.ip += 3;
+        value
+    }
+
+    fn read_constant(&mut self, index: usize) -> Value {
+        self.heap.function(self.frame().function).chunk.constants[index]
+    }
+
+    /// Reads a constant index operand, which is 24 bits for the long form of
+    /// an opcode.
+    fn read_index(&mut self, op: OpCode) -> usize {
+        if op.is_long() {
+            self.read_u24()
+        } else {
+            self.read_byte() as usize
+        }
+    }
+
+    fn read_string(&mut self, op: OpCode) -> Handle {
+        let index = self.read_index(op);
+        match self.read_constant(index) {
+            Value::Obj(name) => name,
+            _ => unreachable!(),
+        }
+    }
+
+    /// Frees every object the program can no longer reach. Only called
+    /// between instructions, when every live value is on the stack, in a
+    /// global, or reachable from a frame or open upvalue.
+    pub fn collect_garbage(&mut self) {
+        let roots = self
+            .stack
+            .iter()
+            .copied()
+            .chain(self.frames.iter().map(|frame| Value::Obj(frame.closure)))
+            .chain(
+                self.open_upvalues
+                    .iter()
+                    .map(|&upvalue| Value::Obj(upvalue)),
+            )
+            .chain(
+                self.globals
+                    .iter()
+                    .flat_map(|(&name, &value)| [Value::Obj(name), value]),
+            )
+            .chain(iter::once(Value::Obj(self.init_string)));
+
+        self.heap.collect(roots);
+    }
+
+    fn run(&mut self) -> Result<Value, VMError> {
+        loop {
+            if self.heap.should_collect() {
+                self.collect_garbage();
+            }
+
             if self.debug {
-                for value in &self.stack {
-                    println!("[{}]", value);
-                }
-                println!("Instruction: {}", &self.chunks[self.index]);
+                (self.tracer.unwrap_or(print_trace))(self);
             }
-            let op = &self.chunks[self.index];
-            dbg!(&self.stack);
+
+            let frame = self.frame();
+            let byte = self.heap.function(frame.function).chunk.code[frame.ip];
+            let Ok(op) = OpCode::try_from(byte) else {
+                return Err(self.runtime_error(
+                    RuntimeErrorKind::InvalidOpcode,
+                    format!("Unknown opcode {}.", byte),
+                ));
+            };
+            self.frame_mut().ip += 1;
             match op {
-                OpCode::Constant(value) => self.stack.push(value.clone()),
-                OpCode::Return => return Ok(()),
+                OpCode::Constant | OpCode::ConstantLong => {
+                    let index = self.read_index(op);
+                    let value = self.read_constant(index);
+                    self.stack.push(value);
+                }
+                OpCode::Return => {
+                    let result = self.stack.pop().unwrap();
+                    let frame = self.frames.pop().unwrap();
+                    self.close_upvalues(frame.slots);
+                    if self.frames.is_empty() {
+                        self.stack.truncate(frame.slots);
+                        return Ok(result);
+                    }
+
+                    self.stack.truncate(frame.slots);
+                    self.stack.push(result);
+                }
                 OpCode::Negate => {
                     let operand = self.stack.pop().unwrap();
                     match operand {
                         Value::Number(num) => self.stack.push(Value::from(-num)),
-                        _ => self.runtime_error("Operand must be a number."),
+                        _ => {
+                            return Err(self.runtime_error(
+                                RuntimeErrorKind::TypeError,
+                                "Operand must be a number.",
+                            ));
+                        }
                     }
                 }
                 OpCode::Add | OpCode::Subtract | OpCode::Multiply | OpCode::Divide => {
-                    self.interpret_bin_op(op.clone())
+                    self.interpret_bin_op(op)?
                 }
                 OpCode::Nil => self.stack.push(Value::Nil),
                 OpCode::True => self.stack.push(Value::Bool(true)),
@@ -82,56 +465,478 @@ impl VM {
                     let a = self.stack.pop().unwrap();
                     self.stack.push(Value::Bool(a == b));
                 }
-                OpCode::Greater => {
+                OpCode::Greater | OpCode::Less => {
                     let b = self.stack.pop().unwrap();
                     let a = self.stack.pop().unwrap();
-                    self.stack.push(Value::Bool(a > b));
-                }
-                OpCode::Less => {
-                    let b = self.stack.pop().unwrap();
-                    let a = self.stack.pop().unwrap();
-                    self.stack.push(Value::Bool(a < b));
+                    match (a, b) {
+                        (Value::Number(a), Value::Number(b)) => {
+                            self.stack.push(Value::Bool(match op {
+                                OpCode::Greater => a > b,
+                                OpCode::Less => a < b,
+                                _ => unreachable!(),
+                            }))
+                        }
+                        _ => {
+                            return Err(self.runtime_error(
+                                RuntimeErrorKind::TypeError,
+                                "Operands must be numbers.",
+                            ));
+                        }
+                    }
                 }
                 OpCode::Print => {
                     let top = self.stack.pop().unwrap();
-                    println!("{}", top);
+                    println!("{}", top.display(&self.heap));
                 }
                 OpCode::Pop => {
                     self.stack.pop();
                 }
-                OpCode::DefineGlobal(name) => {
+                OpCode::DefineGlobal | OpCode::DefineGlobalLong => {
+                    let name = self.read_string(op);
                     let top = self.stack.pop().unwrap();
-                    self.globals.insert(name.to_string(), top);
+                    self.globals.insert(name, top);
+                }
+                OpCode::GetGlobal | OpCode::GetGlobalLong => {
+                    let name = self.read_string(op);
+                    match self.globals.get(&name) {
+                        Some(&value) => self.stack.push(value),
+                        None => {
+                            return Err(self.runtime_error(
+                                RuntimeErrorKind::UndefinedVariable,
+                                format!("Undefined variable '{}'.", self.heap.string(name)),
+                            ));
+                        }
+                    }
+                }
+                OpCode::SetGlobal | OpCode::SetGlobalLong => {
+                    let name = self.read_string(op);
+                    if self.globals.contains_key(&name) {
+                        let value = self.peek(0);
+                        self.globals.insert(name, value);
+                    } else {
+                        return Err(self.runtime_error(
+                            RuntimeErrorKind::UndefinedVariable,
+                            format!("Undefined variable '{}'.", self.heap.string(name)),
+                        ));
+                    }
+                }
+                OpCode::GetLocal => {
+                    let slot = self.read_byte() as usize;
+                    let value = self.stack[self.frame().slots + slot];
+                    self.stack.push(value);
+                }
+                OpCode::SetLocal => {
+                    let slot = self.read_byte() as usize;
+                    let value = self.peek(0);
+                    let index = self.frame().slots + slot;
+                    self.stack[index] = value;
+                }
+                OpCode::Jump => {
+                    let offset = self.read_u16();
+                    self.frame_mut().ip += offset as usize;
+                }
+                OpCode::Loop => {
+                    let offset = self.read_u16();
+                    self.frame_mut().ip -= offset as usize;
                 }
-                OpCode::GetGlobal(name) => match self.globals.get(name) {
-                    Some(value) => self.stack.push(value.clone()),
-                    None => {
-                        self.runtime_error(&format!("Undefined variable '{}'", name));
-                        return Err(VMError::Runtime);
+                OpCode::JumpIfFalse => {
+                    let offset = self.read_u16();
+                    if self.stack.last().unwrap().is_falsey() {
+                        self.frame_mut().ip += offset as usize;
+                    }
+                }
+                OpCode::Call => {
+                    let arg_count = self.read_byte();
+                    let callee = self.peek(arg_count as usize);
+                    self.call_value(callee, arg_count)?;
+                }
+                OpCode::Closure | OpCode::ClosureLong => {
+                    let index = self.read_index(op);
+                    let Value::Obj(function) = self.read_constant(index) else {
+                        unreachable!()
+                    };
+
+                    let upvalue_count = self.heap.function(function).upvalue_count;
+                    let mut upvalues = Vec::with_capacity(upvalue_count);
+                    for _ in 0..upvalue_count {
+                        let is_local = self.read_byte() == 1;
+                        let index = self.read_byte() as usize;
+
+                        upvalues.push(if is_local {
+                            self.capture_upvalue(self.frame().slots + index)
+                        } else {
+                            self.heap.closure(self.frame().closure).upvalues[index]
+                        });
                     }
-                },
-                OpCode::SetGlobal(name) => {
-                    if self.globals.contains_key(name) {
-                        let val = self.stack.last().unwrap();
-                        self.globals.insert(name.to_string(), val.clone());
+
+                    let closure = self
+                        .heap
+                        .alloc(Obj::Closure(Closure { function, upvalues }));
+                    self.stack.push(Value::Obj(closure));
+                }
+                OpCode::GetUpvalue => {
+                    let slot = self.read_byte() as usize;
+                    let upvalue = self.heap.closure(self.frame().closure).upvalues[slot];
+                    let value = match *self.heap.upvalue(upvalue) {
+                        Upvalue::Open(index) => self.stack[index],
+                        Upvalue::Closed(value) => value,
+                    };
+                    self.stack.push(value);
+                }
+                OpCode::SetUpvalue => {
+                    let slot = self.read_byte() as usize;
+                    let upvalue = self.heap.closure(self.frame().closure).upvalues[slot];
+                    let value = self.peek(0);
+                    match self.heap.upvalue_mut(upvalue) {
+                        Upvalue::Open(index) => self.stack[*index] = value,
+                        Upvalue::Closed(closed) => *closed = value,
+                    };
+                }
+                OpCode::CloseUpvalue => {
+                    self.close_upvalues(self.stack.len() - 1);
+                    self.stack.pop();
+                }
+                OpCode::Class | OpCode::ClassLong => {
+                    let name = self.read_string(op);
+                    let class = self.heap.alloc(Obj::Class(Class::new(name)));
+                    self.stack.push(Value::Obj(class));
+                }
+                OpCode::GetProperty | OpCode::GetPropertyLong => {
+                    let name = self.read_string(op);
+                    let Some(instance) = self.as_instance(self.peek(0)) else {
+                        return Err(self.runtime_error(
+                            RuntimeErrorKind::TypeError,
+                            "Only instances have properties.",
+                        ));
+                    };
+
+                    let instance = self.heap.instance(instance);
+                    if let Some(&value) = instance.fields.get(&name) {
+                        self.stack.pop();
+                        self.stack.push(value);
                     } else {
-                        self.runtime_error(&format!("Undefined variable '{}'", name));
-                        return Err(VMError::Runtime);
+                        self.bind_method(instance.class, name)?;
                     }
                 }
+                OpCode::SetProperty | OpCode::SetPropertyLong => {
+                    let name = self.read_string(op);
+                    let Some(instance) = self.as_instance(self.peek(1)) else {
+                        return Err(self.runtime_error(
+                            RuntimeErrorKind::TypeError,
+                            "Only instances have fields.",
+                        ));
+                    };
+
+                    let value = self.peek(0);
+                    self.heap.instance_mut(instance).fields.insert(name, value);
+
+                    let value = self.stack.pop().unwrap();
+                    self.stack.pop();
+                    self.stack.push(value);
+                }
+                OpCode::Method | OpCode::MethodLong => {
+                    let name = self.read_string(op);
+                    let Value::Obj(method) = self.stack.pop().unwrap() else {
+                        unreachable!()
+                    };
+                    let Value::Obj(class) = self.peek(0) else {
+                        unreachable!()
+                    };
+                    self.heap.class_mut(class).methods.insert(name, method);
+                }
+                OpCode::Invoke | OpCode::InvokeLong => {
+                    let name = self.read_string(op);
+                    let arg_count = self.read_byte();
+                    self.invoke(name, arg_count)?;
+                }
+                OpCode::Inherit => {
+                    let Some(Obj::Class(superclass)) = self.heap.object(self.peek(1)) else {
+                        return Err(self.runtime_error(
+                            RuntimeErrorKind::TypeError,
+                            "Superclass must be a class.",
+                        ));
+                    };
+                    let methods = superclass.methods.clone();
+
+                    let Value::Obj(subclass) = self.peek(0) else {
+                        unreachable!()
+                    };
+                    self.heap.class_mut(subclass).methods.extend(methods);
+                    self.stack.pop();
+                }
+                OpCode::GetSuper | OpCode::GetSuperLong => {
+                    let name = self.read_string(op);
+                    let Value::Obj(superclass) = self.stack.pop().unwrap() else {
+                        unreachable!()
+                    };
+                    self.bind_method(superclass, name)?;
+                }
+                OpCode::SuperInvoke | OpCode::SuperInvokeLong => {
+                    let name = self.read_string(op);
+                    let arg_count = self.read_byte();
+                    let Value::Obj(superclass) = self.stack.pop().unwrap() else {
+                        unreachable!()
+                    };
+                    self.invoke_from_class(superclass, name, arg_count)?;
+                }
+                OpCode::GetIndex => {
+                    let (list, index) = self.list_index(self.peek(1), self.peek(0))?;
+                    let item = self.heap.list(list).items[index];
+
+                    self.stack.truncate(self.stack.len() - 2);
+                    self.stack.push(item);
+                }
             }
-            dbg!(&self.stack);
-            dbg!(&self.globals);
-            self.index += 1;
         }
+    }
+
+    /// Checks that `list` is a list and `index` a whole number within it.
+    fn list_index(&self, list: Value, index: Value) -> Result<(Handle, usize), VMError> {
+        let list = match list {
+            Value::Obj(handle) if matches!(self.heap.get(handle), Obj::List(_)) => handle,
+            _ => {
+                return Err(
+                    self.runtime_error(RuntimeErrorKind::TypeError, "Only lists can be indexed.")
+                )
+            }
+        };
+        let index = match index {
+            Value::Number(index) if index.fract() == 0.0 => index,
+            _ => {
+                return Err(self.runtime_error(
+                    RuntimeErrorKind::TypeError,
+                    "List index must be a whole number.",
+                ))
+            }
+        };
+
+        let len = self.heap.list(list).items.len();
+        if index < 0.0 || index >= len as f64 {
+            return Err(self.runtime_error(
+                RuntimeErrorKind::IndexOutOfRange,
+                format!(
+                    "Index {} is out of range for a list of length {}.",
+                    index, len
+                ),
+            ));
+        }
+        Ok((list, index as usize))
+    }
+
+    fn peek(&self, distance: usize) -> Value {
+        self.stack[self.stack.len() - 1 - distance]
+    }
+
+    /// Returns `value`'s handle if it refers to an instance.
+    fn as_instance(&self, value: Value) -> Option<Handle> {
+        match value {
+            Value::Obj(handle) if matches!(self.heap.get(handle), Obj::Instance(_)) => Some(handle),
+            _ => None,
+        }
+    }
+
+    fn capture_upvalue(&mut self, slot: usize) -> Handle {
+        let existing = self.open_upvalues.iter().copied().find(
+            |&upvalue| matches!(*self.heap.upvalue(upvalue), Upvalue::Open(index) if index == slot),
+        );
+
+        if let Some(upvalue) = existing {
+            return upvalue;
+        }
+
+        let upvalue = self.heap.alloc(Obj::Upvalue(Upvalue::Open(slot)));
+        self.open_upvalues.push(upvalue);
+        upvalue
+    }
+
+    /// Closes every open upvalue pointing at `last` or any slot above it,
+    /// moving the captured values off the stack.
+    fn close_upvalues(&mut self, last: usize) {
+        let stack = &self.stack;
+        let heap = &mut self.heap;
+        self.open_upvalues.retain(|&upvalue| {
+            let upvalue = heap.upvalue_mut(upvalue);
+            match *upvalue {
+                Upvalue::Open(index) if index >= last => {
+                    *upvalue = Upvalue::Closed(stack[index]);
+                    false
+                }
+                _ => true,
+            }
+        });
+    }
+
+    fn call_value(&mut self, callee: Value, arg_count: u8) -> Result<(), VMError> {
+        let Value::Obj(handle) = callee else {
+            return Err(self.runtime_error(
+                RuntimeErrorKind::TypeError,
+                "Can only call functions and classes.",
+            ));
+        };
+
+        match self.heap.get(handle) {
+            Obj::Closure(_) => self.call(handle, arg_count),
+            Obj::Class(class) => {
+                let initializer = class.methods.get(&self.init_string).copied();
+                let instance = self.heap.alloc(Obj::Instance(Instance::new(handle)));
+                let slot = self.stack.len() - arg_count as usize - 1;
+                self.stack[slot] = Value::Obj(instance);
+
+                match initializer {
+                    Some(initializer) => self.call(initializer, arg_count),
+                    None if arg_count != 0 => Err(self.runtime_error(
+                        RuntimeErrorKind::ArityMismatch,
+                        format!("Expected 0 arguments but got {}.", arg_count),
+                    )),
+                    None => Ok(()),
+                }
+            }
+            Obj::Native(native) => {
+                let Native {
+                    arity, function, ..
+                } = *native;
+                if arg_count as usize != arity {
+                    return Err(self.runtime_error(
+                        RuntimeErrorKind::ArityMismatch,
+                        format!("Expected {} arguments but got {}.", arity, arg_count),
+                    ));
+                }
+
+                let args_start = self.stack.len() - arg_count as usize;
+                let args = self.stack[args_start..].to_vec();
+                match function(self, &args) {
+                    Ok(result) => {
+                        self.stack.truncate(args_start - 1);
+                        self.stack.push(result);
+                        Ok(())
+                    }
+                    Err(RuntimeError {
+                        kind: RuntimeErrorKind::Exit(status),
+                        ..
+                    }) => Err(VMError::Exit(status)),
+                    Err(error) => Err(VMError::Runtime(self.locate(error))),
+                }
+            }
+            Obj::BoundMethod(BoundMethod { receiver, method }) => {
+                let method = *method;
+                let slot = self.stack.len() - arg_count as usize - 1;
+                self.stack[slot] = *receiver;
+                self.call(method, arg_count)
+            }
+            _ => Err(self.runtime_error(
+                RuntimeErrorKind::TypeError,
+                "Can only call functions and classes.",
+            )),
+        }
+    }
+
+    /// Calls a method directly on the receiver sitting `arg_count` slots
+    /// below the top of the stack, without allocating a bound method.
+    fn invoke(&mut self, name: Handle, arg_count: u8) -> Result<(), VMError> {
+        let Some(instance) = self.as_instance(self.peek(arg_count as usize)) else {
+            return Err(
+                self.runtime_error(RuntimeErrorKind::TypeError, "Only instances have methods.")
+            );
+        };
+
+        let instance = self.heap.instance(instance);
+        if let Some(&field) = instance.fields.get(&name) {
+            let slot = self.stack.len() - arg_count as usize - 1;
+            self.stack[slot] = field;
+            return self.call_value(field, arg_count);
+        }
+
+        self.invoke_from_class(instance.class, name, arg_count)
+    }
+
+    fn invoke_from_class(
+        &mut self,
+        class: Handle,
+        name: Handle,
+        arg_count: u8,
+    ) -> Result<(), VMError> {
+        let Some(&method) = self.heap.class(class).methods.get(&name) else {
+            return Err(self.runtime_error(
+                RuntimeErrorKind::UndefinedProperty,
+                format!("Undefined property '{}'.", self.heap.string(name)),
+            ));
+        };
+
+        self.call(method, arg_count)
+    }
+
+    /// Replaces the instance on top of the stack with its class's method
+    /// `name` bound to that instance.
+    fn bind_method(&mut self, class: Handle, name: Handle) -> Result<(), VMError> {
+        let Some(&method) = self.heap.class(class).methods.get(&name) else {
+            return Err(self.runtime_error(
+                RuntimeErrorKind::UndefinedProperty,
+                format!("Undefined property '{}'.", self.heap.string(name)),
+            ));
+        };
+
+        let receiver = self.stack.pop().unwrap();
+        let bound = self
+            .heap
+            .alloc(Obj::BoundMethod(BoundMethod { receiver, method }));
+        self.stack.push(Value::Obj(bound));
         Ok(())
     }
 
-    fn runtime_error(&self, message: &str) {
-        dbg!(message);
+    fn call(&mut self, closure: Handle, arg_count: u8) -> Result<(), VMError> {
+        let function = self.heap.closure(closure).function;
+        let arity = self.heap.function(function).arity;
+        if arg_count as usize != arity {
+            return Err(self.runtime_error(
+                RuntimeErrorKind::ArityMismatch,
+                format!("Expected {} arguments but got {}.", arity, arg_count),
+            ));
+        }
+
+        if self.frames.len() == FRAMES_MAX {
+            return Err(self.runtime_error(RuntimeErrorKind::StackOverflow, "Stack overflow."));
+        }
+
+        self.frames.push(CallFrame {
+            closure,
+            function,
+            ip: 0,
+            slots: self.stack.len() - arg_count as usize - 1,
+        });
+        Ok(())
+    }
+
+    fn runtime_error(&self, kind: RuntimeErrorKind, message: impl Into<String>) -> VMError {
+        VMError::Runtime(self.locate(RuntimeError::new(kind, message)))
+    }
+
+    /// Attaches the call stack to `error`, and the current instruction's
+    /// location if it was compiled from the same source as the script being
+    /// run.
+    fn locate(&self, mut error: RuntimeError) -> RuntimeError {
+        error.trace = self
+            .frames
+            .iter()
+            .rev()
+            .map(|frame| {
+                let function = self.heap.function(frame.function);
+                TraceFrame {
+                    function: function.name.clone(),
+                    location: function.chunk.location(frame.ip.saturating_sub(1)),
+                }
+            })
+            .collect();
+
+        let source = |frame: Option<&CallFrame>| {
+            frame.map(|frame| self.heap.function(frame.function).source)
+        };
+        if source(self.frames.last()) == source(self.frames.first()) {
+            error.location = error.trace.first().map(|frame| frame.location);
+        }
+        error
     }
 
-    fn interpret_bin_op(&mut self, op: OpCode) {
+    fn interpret_bin_op(&mut self, op: OpCode) -> Result<(), VMError> {
         let b = self.stack.pop().unwrap();
         let a = self.stack.pop().unwrap();
 
@@ -143,12 +948,575 @@ impl VM {
                 OpCode::Divide => a / b,
                 _ => unreachable!(),
             })),
-            (Value::Obj(Obj::String(a)), Value::Obj(Obj::String(b))) => {
-                let mut new_str = a;
-                new_str.push_str(&b);
-                self.stack.push(Value::Obj(Obj::String(new_str)));
+            (a, b) if op == OpCode::Add => {
+                let (Some(Obj::String(a)), Some(Obj::String(b))) =
+                    (self.heap.object(a), self.heap.object(b))
+                else {
+                    return Err(self.runtime_error(
+                        RuntimeErrorKind::TypeError,
+                        "Operands must be two numbers or two strings.",
+                    ));
+                };
+
+                let mut new_str = String::with_capacity(a.chars.len() + b.chars.len());
+                new_str.push_str(a.as_str());
+                new_str.push_str(b.as_str());
+                let string = self.heap.intern(&new_str);
+                self.stack.push(Value::Obj(string));
+            }
+            _ => {
+                return Err(
+                    self.runtime_error(RuntimeErrorKind::TypeError, "Operands must be numbers.")
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, env};
+
+    use super::*;
+
+    fn runtime_error(source: &str) -> RuntimeError {
+        match VM::new().interpret(source) {
+            Err(VMError::Runtime(error)) => error,
+            result => panic!("expected a runtime error, got {:?}", result),
+        }
+    }
+
+    /// Evaluates `source` as a REPL entry and returns its value as the REPL
+    /// would echo it.
+    fn repr(vm: &mut VM, source: &str) -> String {
+        let value = vm.eval(source).unwrap();
+        value.repr(&vm.heap).to_string()
+    }
+
+    #[test]
+    fn tracer_sees_every_instruction() {
+        thread_local! {
+            static TRACED: RefCell<Vec<OpCode>> = const { RefCell::new(vec![]) };
+        }
+
+        fn record(vm: &VM) {
+            let frame = vm.frames.last().unwrap();
+            let op = vm
+                .heap
+                .function(frame.function)
+                .chunk
+                .opcode(frame.ip)
+                .unwrap();
+            TRACED.with(|traced| traced.borrow_mut().push(op));
+        }
+
+        let mut vm = VM::new();
+        vm.set_tracer(record);
+        vm.interpret("var a = 1; a = -a;").unwrap();
+
+        let traced = TRACED.with(|traced| traced.take());
+        assert_eq!(
+            traced,
+            [
+                OpCode::Constant,
+                OpCode::DefineGlobal,
+                OpCode::GetGlobal,
+                OpCode::Negate,
+                OpCode::SetGlobal,
+                OpCode::Pop,
+                OpCode::Nil,
+                OpCode::Return,
+            ]
+        );
+    }
+
+    #[test]
+    fn negate_non_number() {
+        let error = runtime_error("-\"a\";");
+        assert_eq!(error.kind, RuntimeErrorKind::TypeError);
+        assert_eq!(error.location.map(|location| location.line), Some(1));
+    }
+
+    #[test]
+    fn subtract_strings() {
+        let error = runtime_error("\"a\" - \"b\";");
+        assert_eq!(error.kind, RuntimeErrorKind::TypeError);
+    }
+
+    #[test]
+    fn undefined_variable() {
+        let error = runtime_error("print a;");
+        assert_eq!(error.kind, RuntimeErrorKind::UndefinedVariable);
+        assert_eq!(error.message, "Undefined variable 'a'.");
+    }
+
+    #[test]
+    fn undefined_property() {
+        let error = runtime_error("class A {} A().missing;");
+        assert_eq!(error.kind, RuntimeErrorKind::UndefinedProperty);
+    }
+
+    #[test]
+    fn arity_mismatch_trace() {
+        let error = runtime_error("fun f(a) {}\nfun g() {\n  f();\n}\ng();");
+        assert_eq!(error.kind, RuntimeErrorKind::ArityMismatch);
+        assert_eq!(error.location.map(|location| location.line), Some(3));
+
+        let trace: Vec<_> = error.trace.iter().map(ToString::to_string).collect();
+        assert_eq!(trace, ["[line 3] in g()", "[line 5] in script"]);
+    }
+
+    #[test]
+    fn render_runtime_error() {
+        let source = "fun f(x) {\n  return -x;\n}\nf(\"a\");";
+        let error = VMError::Runtime(runtime_error(source));
+
+        insta::assert_snapshot!(error.render("test.lox", source, false));
+    }
+
+    #[test]
+    fn errors_in_earlier_entries_render_without_a_snippet() {
+        let mut vm = VM::new();
+        vm.eval("fun f(x) {\n  return -x;\n}").unwrap();
+
+        let entry = "f(\"a\")";
+        let error = vm.eval(entry).unwrap_err();
+        assert_eq!(
+            error.render("<repl>", entry, false),
+            "error: Operand must be a number.\n[line 2] in f()\n[line 1] in script\n"
+        );
+
+        let entry = "fun g() { return -\"b\"; } g()";
+        let VMError::Runtime(error) = vm.eval(entry).unwrap_err() else {
+            panic!("expected a runtime error");
+        };
+        assert_eq!(error.location.map(|location| location.column), Some(18));
+    }
+
+    fn global(vm: &mut VM, name: &str) -> String {
+        let name = vm.heap.intern(name);
+        vm.globals[&name].display(&vm.heap).to_string()
+    }
+
+    #[test]
+    fn survives_stress_gc() {
+        let mut vm = VM::new();
+        vm.heap.stress = true;
+        vm.interpret(
+            "class Node { init(value, next) { this.value = value; this.next = next; } }
+             fun counter() { var n = 0; fun inc() { n = n + 1; return n; } return inc; }
+             var count = counter();
+             var list = nil;
+             for (var i = 0; i < 5; i = i + 1) list = Node(\"n\" + \"ode\", list);
+             var result = \"\";
+             while (list != nil) { result = result + list.value; list = list.next; count(); }
+             result = result + \" \" + (count() == 6 and \"counted\");",
+        )
+        .unwrap();
+
+        assert_eq!(global(&mut vm, "result"), "nodenodenodenodenode counted");
+    }
+
+    #[test]
+    fn collects_unreachable_cycles() {
+        let mut vm = VM::new();
+        vm.interpret(
+            "class A {}
+             fun make() { var a = A(); a.self = a; return a; }
+             for (var i = 0; i < 1000; i = i + 1) { var a = make(); a.other = make(); }",
+        )
+        .unwrap();
+        let allocated = vm.heap.len();
+
+        vm.collect_garbage();
+        assert!(vm.heap.len() < allocated - 2000);
+    }
+
+    #[test]
+    fn eval_returns_final_expression() {
+        let mut vm = VM::new();
+        assert_eq!(vm.eval("1 + 2").unwrap(), Value::Number(3.0));
+        assert_eq!(vm.eval("var a = 1;").unwrap(), Value::Nil);
+        assert_eq!(vm.eval("a = a + 1; a;").unwrap(), Value::Number(2.0));
+        assert_eq!(vm.eval("fun f() { return 1; }").unwrap(), Value::Nil);
+    }
+
+    #[test]
+    fn eval_runs_loops_ending_the_entry_to_completion() {
+        let mut vm = VM::new();
+        let result = vm.eval("var i = 0; while (i < 3) i = i + 1;").unwrap();
+        assert_eq!(result, Value::Nil);
+        assert_eq!(vm.eval("i").unwrap(), Value::Number(3.0));
+
+        vm.eval("var n = 0; for (var j = 0; j < 4; j = j + 1) n = n + 1;")
+            .unwrap();
+        vm.eval("if (n == 4) n = 10;").unwrap();
+        assert_eq!(vm.eval("n").unwrap(), Value::Number(10.0));
+    }
+
+    #[test]
+    fn repr_marks_types() {
+        let mut vm = VM::new();
+        let mut repr = |source: &str| {
+            let value = vm.eval(source).unwrap();
+            value.repr(&vm.heap).to_string()
+        };
+
+        assert_eq!(repr("\"a\" + \"\nb\""), "\"a\\nb\"");
+        assert_eq!(repr("fun f() {} f"), "<fn f>");
+        assert_eq!(repr("class A {} A"), "<class A>");
+        assert_eq!(repr("A()"), "A instance");
+        assert_eq!(repr("clock"), "<native fn clock>");
+        assert_eq!(repr("true"), "true");
+    }
+
+    #[test]
+    fn closures_keep_captured_variables_alive() {
+        let mut vm = VM::new();
+        vm.interpret(
+            "fun makeCounter() {
+               var count = 0;
+               fun increment() { count = count + 1; return count; }
+               return increment;
+             }
+             var counter = makeCounter();
+             var other = makeCounter();
+             counter();",
+        )
+        .unwrap();
+
+        assert_eq!(repr(&mut vm, "counter()"), "2");
+        assert_eq!(repr(&mut vm, "other()"), "1");
+
+        vm.interpret(
+            "fun outer() {
+               var x = \"outside\";
+               fun middle() { fun inner() { return x; } return inner; }
+               return middle();
+             }",
+        )
+        .unwrap();
+        assert_eq!(repr(&mut vm, "outer()()"), "\"outside\"");
+    }
+
+    #[test]
+    fn closures_share_captured_variables() {
+        let mut vm = VM::new();
+        vm.interpret(
+            "var getter;
+             var setter;
+             {
+               var shared = 1;
+               fun get() { return shared; }
+               fun set(value) { shared = value; }
+               getter = get;
+               setter = set;
+             }
+             setter(42);",
+        )
+        .unwrap();
+
+        assert_eq!(repr(&mut vm, "getter()"), "42");
+    }
+
+    #[test]
+    fn closures_in_a_loop_capture_each_iteration() {
+        let mut vm = VM::new();
+        vm.interpret(
+            "var first;
+             var second;
+             for (var i = 0; i < 2; i = i + 1) {
+               var j = i;
+               fun show() { return j; }
+               if (j == 0) first = show; else second = show;
+             }",
+        )
+        .unwrap();
+
+        assert_eq!(repr(&mut vm, "first()"), "0");
+        assert_eq!(repr(&mut vm, "second()"), "1");
+    }
+
+    #[test]
+    fn instances_hold_fields() {
+        let mut vm = VM::new();
+        vm.interpret(
+            "class Pair {}
+             var pair = Pair();
+             pair.first = 1;
+             pair.second = 2;
+             class Node {}
+             var a = Node();
+             var b = Node();
+             a.next = b;
+             b.value = \"end\";",
+        )
+        .unwrap();
+
+        assert_eq!(repr(&mut vm, "pair.first + pair.second"), "3");
+        assert_eq!(repr(&mut vm, "pair.first = 5"), "5");
+        assert_eq!(repr(&mut vm, "pair.first"), "5");
+        assert_eq!(repr(&mut vm, "a.next.value"), "\"end\"");
+        assert_eq!(repr(&mut vm, "a == a"), "true");
+        assert_eq!(repr(&mut vm, "a == b"), "false");
+
+        let error = runtime_error("class A {} A().missing;");
+        assert_eq!(error.kind, RuntimeErrorKind::UndefinedProperty);
+        assert_eq!(error.message, "Undefined property 'missing'.");
+        assert_eq!(
+            runtime_error("var x = 1; x.y = 2;").message,
+            "Only instances have fields."
+        );
+    }
+
+    #[test]
+    fn methods_bind_this() {
+        let mut vm = VM::new();
+        vm.interpret(
+            "class Counter {
+               init(start) { this.count = start; }
+               bump() { this.count = this.count + 1; return this; }
+             }
+             var counter = Counter(10);
+             class Greeter {
+               init(name) { this.name = name; return; }
+               greeting() { fun say() { return \"hi \" + this.name; } return say; }
+             }
+             var greeter = Greeter(\"bob\");
+             fun standalone() { return \"field\"; }
+             greeter.field = standalone;",
+        )
+        .unwrap();
+
+        assert_eq!(repr(&mut vm, "counter.bump().bump().count"), "12");
+        assert_eq!(
+            repr(&mut vm, "var bump = counter.bump; bump(); counter.count"),
+            "13"
+        );
+        assert_eq!(repr(&mut vm, "bump"), "<fn bump>");
+        assert_eq!(repr(&mut vm, "greeter.greeting()()"), "\"hi bob\"");
+        assert_eq!(repr(&mut vm, "greeter.init(\"ann\").name"), "\"ann\"");
+        assert_eq!(repr(&mut vm, "greeter.field()"), "\"field\"");
+
+        let error = runtime_error("class A { init(a) {} } A();");
+        assert_eq!(error.kind, RuntimeErrorKind::ArityMismatch);
+        assert_eq!(
+            runtime_error("var x = 1; x.f();").message,
+            "Only instances have methods."
+        );
+    }
+
+    #[test]
+    fn subclasses_inherit_and_call_super() {
+        let mut vm = VM::new();
+        vm.interpret(
+            "class Animal {
+               init(name) { this.name = name; }
+               speak() { return this.name + \" makes a sound\"; }
+               describe() { return this.speak(); }
+             }
+             class Dog < Animal {
+               init(name) { super.init(name); this.tricks = 0; }
+               speak() { return super.speak() + \": woof\"; }
+               parentSpeak() { return super.speak; }
+             }
+             var dog = Dog(\"rex\");",
+        )
+        .unwrap();
+
+        assert_eq!(
+            repr(&mut vm, "dog.describe()"),
+            "\"rex makes a sound: woof\""
+        );
+        assert_eq!(
+            repr(&mut vm, "dog.parentSpeak()()"),
+            "\"rex makes a sound\""
+        );
+        assert_eq!(repr(&mut vm, "dog.tricks"), "0");
+        assert_eq!(
+            repr(&mut vm, "Animal(\"cat\").speak()"),
+            "\"cat makes a sound\""
+        );
+
+        let error = runtime_error("var x = 1; class A < x {}");
+        assert_eq!(error.kind, RuntimeErrorKind::TypeError);
+        assert_eq!(error.message, "Superclass must be a class.");
+    }
+
+    #[test]
+    fn runs_chunks_with_more_than_256_constants() {
+        let padding: String = (0..300).map(|n| format!("{};", n)).collect();
+        let mut vm = VM::new();
+        vm.interpret(&format!(
+            "{padding}
+             var late = 1;
+             class A {{ m() {{ return late; }} }}
+             class B < A {{
+               m() {{ {padding} var get = super.m; return super.m() + get(); }}
+             }}
+             var b = B();
+             b.field = b.m();
+             late = b.field;
+             fun f() {{ return late; }}"
+        ))
+        .unwrap();
+        assert_eq!(repr(&mut vm, "f()"), "2");
+
+        let globals: String = (0..130).map(|n| format!("var g{n} = {n};")).collect();
+        vm.interpret(&globals).unwrap();
+        assert_eq!(repr(&mut vm, "g129"), "129");
+    }
+
+    #[test]
+    fn calls_host_defined_natives() {
+        fn add(_vm: &mut VM, args: &[Value]) -> Result<Value, RuntimeError> {
+            match args {
+                [Value::Number(a), Value::Number(b)] => Ok(Value::from(a + b)),
+                _ => Err(RuntimeError::new(
+                    RuntimeErrorKind::Native,
+                    "add() takes two numbers.",
+                )),
             }
-            _ => self.runtime_error("Operands must be two numbers or two strings."),
         }
+
+        let mut vm = VM::new();
+        vm.define_native("add", 2, add);
+        assert_eq!(vm.eval("add(1, add(2, 3))").unwrap(), Value::Number(6.0));
+
+        let Err(VMError::Runtime(error)) = vm.interpret("add(1);") else {
+            panic!("expected an arity error");
+        };
+        assert_eq!(error.kind, RuntimeErrorKind::ArityMismatch);
+        assert_eq!(error.message, "Expected 2 arguments but got 1.");
+
+        let Err(VMError::Runtime(error)) =
+            vm.interpret("fun f() {\n  return add(1, nil);\n}\nf();")
+        else {
+            panic!("expected the native's error");
+        };
+        assert_eq!(error.kind, RuntimeErrorKind::Native);
+        assert_eq!(error.message, "add() takes two numbers.");
+        assert_eq!(error.location.unwrap().line, 2);
+        assert_eq!(
+            error
+                .trace
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            ["[line 2] in f()", "[line 4] in script"]
+        );
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn scripts_see_arguments_and_environment() {
+        let mut vm = VM::new();
+        vm.args = vec!["a".to_string(), "b c".to_string()];
+
+        assert_eq!(repr(&mut vm, "args()"), "[\"a\", \"b c\"]");
+        assert_eq!(repr(&mut vm, "args()[1]"), "\"b c\"");
+        assert_eq!(repr(&mut vm, "len(args()) + len(\"abc\")"), "5");
+
+        let path = vm.eval("env(\"PATH\")").unwrap();
+        assert_eq!(
+            path.display(&vm.heap).to_string(),
+            env::var("PATH").unwrap()
+        );
+        assert_eq!(
+            vm.eval("env(\"BYTELOX_SURELY_UNSET\")").unwrap(),
+            Value::Nil
+        );
+    }
+
+    #[test]
+    fn indexing_checks_the_list_and_index() {
+        let error = runtime_error("args()[0];");
+        assert_eq!(error.kind, RuntimeErrorKind::IndexOutOfRange);
+        assert_eq!(
+            error.message,
+            "Index 0 is out of range for a list of length 0."
+        );
+        assert_eq!(
+            runtime_error("args()[0.5];").kind,
+            RuntimeErrorKind::TypeError
+        );
+        assert_eq!(
+            runtime_error("nil[0];").message,
+            "Only lists can be indexed."
+        );
+    }
+
+    #[test]
+    fn exit_stops_the_script_without_ending_the_process() {
+        let mut vm = VM::new();
+        let result = vm.interpret("var before = 1; fun f() { exit(3); } f(); before = 2;");
+
+        assert!(matches!(result, Err(VMError::Exit(3))));
+        assert!(vm.stack.is_empty() && vm.frames.is_empty());
+        assert_eq!(vm.eval("before").unwrap(), Value::Number(1.0));
+    }
+
+    #[test]
+    fn snippets_share_globals() {
+        let mut vm = VM::new();
+        vm.interpret("var a = 1; fun get() { return a; }").unwrap();
+        vm.interpret("a = a + 1;").unwrap();
+
+        assert_eq!(vm.eval("get()").unwrap(), Value::Number(2.0));
+        assert!(vm.stack.is_empty());
+    }
+
+    #[test]
+    fn errors_do_not_poison_later_snippets() {
+        let mut vm = VM::new();
+        assert!(vm.interpret("var a = ;").is_err());
+        vm.interpret("var a = 1;").unwrap();
+
+        let error = vm.interpret("fun f() { var b = a; a = 2; return -\"x\"; } f();");
+        assert!(matches!(error, Err(VMError::Runtime(_))));
+        assert!(vm.stack.is_empty() && vm.frames.is_empty());
+
+        assert_eq!(vm.eval("a").unwrap(), Value::Number(2.0));
+    }
+
+    #[test]
+    fn escaped_closures_survive_runtime_errors() {
+        let mut vm = VM::new();
+        let error = vm.interpret(
+            "var g;
+             fun f() { var x = \"captured\"; fun h() { return x; } g = h; nil(); }
+             f();",
+        );
+        assert!(error.is_err());
+        assert!(vm.open_upvalues.is_empty());
+
+        vm.interpret("var padding = 1;").unwrap();
+        let value = vm.eval("g()").unwrap();
+        assert_eq!(value.display(&vm.heap).to_string(), "captured");
+    }
+
+    #[test]
+    fn stack_overflow() {
+        let error = runtime_error("fun f() { f(); } f();");
+        assert_eq!(error.kind, RuntimeErrorKind::StackOverflow);
+        assert_eq!(error.trace.len(), FRAMES_MAX);
+        assert_eq!(
+            error.to_string(),
+            format!(
+                "Stack overflow.\n[line 1] in f()\n... {} more\n[line 1] in script",
+                FRAMES_MAX - 2
+            )
+        );
+    }
+
+    #[test]
+    fn recurses_deeper_than_a_few_frames() {
+        let mut vm = VM::new();
+        vm.interpret(
+            "fun depth(n) { if (n == 0) return 0; return depth(n - 1) + 1; } var d = depth(1000);",
+        )
+        .unwrap();
+        assert_eq!(global(&mut vm, "d"), "1000");
     }
 }